@@ -1,7 +1,8 @@
 //use itertools::Itertools;
 use dashmap::DashMap;
+use numpy::IntoPyArray;
 use pyo3::prelude::*;
-use rayon::prelude::*;
+use pyo3::types::IntoPyDict;
 use std::collections::HashMap;
 use std::usize;
 
@@ -14,20 +15,21 @@ pub const D_MAX: usize = 25;
 // Need to include a variety of different policies.
 // Once this is done you just need to call rust::value_function::value_function_pol_eval and pass the normal constructors + action
 #[pyfunction]
-#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, base_stock_vals=(14,7,7) ,transhipment_policy='N',num_cores=4, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, ordering_policy='R',order_cap=None))]
+#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, base_stock_vals=(14,7,7) ,transhipment_policy='N',num_cores=4, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None, ordering_policy='R',order_cap=None, tie_seed=None, allocation_rule=None, optimise_warehouse=false, hold_back=None, hold_back_trigger=None, lookahead_steps=1, rollout_base='N', rollout_simulations=None, rollout_seed=0, all_periods=false, terminal_unit_cost=None, terminal_values=None, progress=None, as_array=false))]
 fn policy_evaluation_par_bs(
+    py: Python<'_>,
     periods: usize,
     sa_demand_param_one: f64,
     sb_demand_param_one: f64,
-    h_s: f64,
+    h_s: rust::policy_contructor::PerStore,
     h_w: f64,
-    c_u_s: f64,
-    c_p: f64,
-    c_ts: f64,
+    c_u_s: rust::policy_contructor::PerStore,
+    c_p: rust::policy_contructor::PerStore,
+    c_ts: rust::policy_contructor::PerDirection,
     base_stock_vals: Option<(usize, usize, usize)>,
     transhipment_policy: Option<char>,
     num_cores: Option<usize>,
-    p: Option<f64>,
+    p: Option<rust::policy_contructor::PerStore>,
     sa_demand_param_two: Option<f64>,
     sb_demand_param_two: Option<f64>,
     distribution: Option<char>,
@@ -35,42 +37,51 @@ fn policy_evaluation_par_bs(
     max_sa: Option<usize>,
     max_sb: Option<usize>,
     gamma: Option<f64>,
+    c_ts_fixed: f64,
+    dfw_order: Option<rust::policy_contructor::DfwOrder>,
+    dfw_threshold: usize,
+    substitution: Option<rust::policy_contructor::PerStore>,
+    online_demand_param_one: Option<f64>,
+    online_demand_param_two: Option<f64>,
+    c_u_w: f64,
+    c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
     ordering_policy: Option<char>,
     order_cap: Option<(usize,usize)>,
-) -> PyResult<(
-    HashMap<(usize, usize, usize, usize), (usize, usize, usize, usize, usize)>,
-    HashMap<(usize, usize, usize), f64>,
-)> {
+    tie_seed: Option<u64>,
+    allocation_rule: Option<char>,
+    optimise_warehouse: bool,
+    hold_back: Option<(usize, usize)>,
+    hold_back_trigger: Option<(usize, usize)>,
+    lookahead_steps: usize,
+    rollout_base: char,
+    rollout_simulations: Option<usize>,
+    rollout_seed: u64,
+    all_periods: bool,
+    terminal_unit_cost: Option<(f64, f64, f64)>,
+    terminal_values: Option<HashMap<(usize, usize, usize), f64>>,
+    progress: Option<PyObject>,
+    as_array: bool,
+) -> PyResult<PyObject> {
     let base_stock_policy = base_stock_vals.unwrap_or((14, 7, 7));
     // Stores all the infrastructure for the parameters in the optimal policy
+    let model = rust::policy_contructor::ModelParams::new(
+        sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+        sa_demand_param_two, sb_demand_param_two, distribution, max_wh, max_sa, max_sb, gamma, dfw_order,
+        dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w, c_ts_emergency,
+    );
     let policy_constructor = rust::policy_contructor::OptimalPolicy::new(
-        sa_demand_param_one,
-        sb_demand_param_one,
-        h_s,
-        h_w,
-        c_u_s,
-        c_p,
-        c_ts,
-        base_stock_policy.1,
-        base_stock_policy.2,
-        p,
-        sa_demand_param_two,
-        sb_demand_param_two,
-        distribution,
-        max_wh,
-        max_sa,
-        max_sb,
-        gamma,
+        &model,
+        (base_stock_policy.1, base_stock_policy.2),
     );
-    let store_expectation = policy_constructor.expectation_all_stores();
-    let warehouse_expectation = policy_constructor.expectation_all_warehouse();
-
     // Implement transhipment policy
-    // Can be 'N' - No transhipment, 'T' - TIE, 'E' - ESR or 'L' - Lookahead Policy, 'O' - one step ahead no transhipment
+    // Can be 'N' - No transhipment, 'T' - TIE, 'E' - ESR or 'L' - Lookahead Policy, 'O' - one step ahead no transhipment,
+    // 'K' - k step lookahead over lookahead_steps periods with a base-stock tail, 'R' - rollout on the base heuristic rollout_base
     let transhipment_policy = transhipment_policy.unwrap_or('N');
     // Implement ordering policy, can be 'R' for regular base-stock, 'E' for echelon base-stock, 'C' for regular with order caps and 'S' for echelon with order caps
     // Only R and C implemented right now
     let ordering_policy: char=  ordering_policy.unwrap_or('R');
+    // Allocation rule when the warehouse is short of the store orders, 'R' - larger remaining request, 'P' - proportional to request,
+    // 'M' - proportional to mean demand, 'A' or 'B' - priority to that store, 'E' - balanced run-out
 
     let store_a_expectation_mean = rust::distributions::generate_distributions::distribution_mean(
         distribution.unwrap_or('P'),
@@ -83,21 +94,37 @@ fn policy_evaluation_par_bs(
         sb_demand_param_two,
     );
 
-    // generate the one step ahead expectations to use later (pregenerated hashmap for easier reading)
-    let one_step_ahead_expectations = if transhipment_policy == 'E' {
-        policy_constructor.all_one_step_ahead_out()
-    } else {
-        (HashMap::new(), HashMap::new()) // Create empty hashmap if not needed
-    };
+    // Load in terminal cost (zero unless a per-unit cost or value table is given)
+    let v: DashMap<(usize, usize, usize), f64> = rust::value_function::terminal_cost(
+        &policy_constructor,
+        terminal_unit_cost,
+        terminal_values.as_ref(),
+    )?;
 
-    // generate one step lookahead expectations for the lookahead if needed (and the one step no transhipment lookahead)
-    let one_step_lookahead_expectations = if (transhipment_policy == 'L')
-        | (transhipment_policy == 'O')
-    {
-        policy_constructor.all_one_step_ahead_la(store_a_expectation_mean, store_b_expectation_mean)
-    } else {
-        (HashMap::new(), HashMap::new()) // Create empty hashmap if not needed
-    };
+    let mut policy = rust::policies::heuristic_policy(
+        &policy_constructor,
+        transhipment_policy,
+        ordering_policy,
+        base_stock_policy,
+        order_cap,
+        (store_a_expectation_mean, store_b_expectation_mean),
+        tie_seed,
+        allocation_rule,
+        optimise_warehouse,
+        hold_back
+            .zip(hold_back_trigger)
+            .map(|(hold_back, trigger)| rust::policies::hold_back::HoldBackLevels {
+                hold_back,
+                trigger,
+            }),
+        lookahead_steps,
+        &rust::policies::rollout::RolloutOptions {
+            base: rollout_base,
+            simulations: rollout_simulations,
+            seed: rollout_seed,
+        },
+        Some(&v.iter().map(|entry| (*entry.key(), *entry.value())).collect()),
+    )?;
 
     // Create the thread pool
     rayon::ThreadPoolBuilder::new()
@@ -105,161 +132,80 @@ fn policy_evaluation_par_bs(
         .build()
         .unwrap();
 
-    // Load in terminal cost (assume zero for now)
-    let v: DashMap<(usize, usize, usize), f64> =
-        rust::value_function::terminal_cost(&policy_constructor, None);
-    let optimal_pol: DashMap<(usize, usize, usize, usize), (usize, usize, usize, usize, usize)> =
-        DashMap::new();
-    // Iterate through periods
-
-    for t in (1..periods).rev() {
-        //println!("Period: {:?}", t);
-        // Save previous iteration (v_t+1)
-        let v_plus_1 = v.clone();
-        //v.clear(); // Reset V to repopulate
-
-        // Iterate through all states
-        let state_space: Vec<(usize, usize, usize)> = policy_constructor
-            .construct_state_space_iterator()
-            .collect();
-        //println!("Length of state space: {:?}",state_space.len());
-        state_space.clone().par_iter().for_each(|state| {
-            //println!("Testing state {:?} out of {:?}. Current: {:?}",state, state_space.len(), state);
-            let state = (state.0, state.1, state.2);
-            let v_plus_1_hm = v_plus_1
-                .clone()
-                .into_iter()
-                .collect::<HashMap<(usize, usize, usize), f64>>();
-
-            let ordering_action: (usize, usize, usize);
-            let transhipment_action: (usize, usize);
-
-            if transhipment_policy == 'L' {
-                let final_period = t == periods - 1;
-                let lookahead_action = rust::policies::lookahead::calculate_lookahead(
-                    &policy_constructor,
-                    &one_step_lookahead_expectations,
-                    state,
-                    base_stock_policy.0,
-                    final_period,
-                );
-                ordering_action = (lookahead_action.0, lookahead_action.1, lookahead_action.2);
-                transhipment_action = (lookahead_action.3, lookahead_action.4);
-            } else if transhipment_policy == 'O' {
-                let final_period = t == periods - 1;
-                let lookahead_no_transhipment_action =
-                    rust::policies::lookahead::calculate_lookahead_no_transhipment(
-                        &one_step_lookahead_expectations,
-                        state,
-                        base_stock_policy.0,
-                        final_period,
-                    );
-                ordering_action = lookahead_no_transhipment_action;
-                transhipment_action = (0,0);
-            } else {
-                transhipment_action = if transhipment_policy == 'N' {
-                    (0 as usize, 0 as usize)
-                } else if transhipment_policy == 'T' {
-                    rust::policies::tie::calculate_tie(
-                        state.1,
-                        state.2,
-                        store_a_expectation_mean,
-                        store_b_expectation_mean,
-                        max_sa.unwrap_or(10),
-                        max_sb.unwrap_or(10),
-                    )
-                } else if transhipment_policy == 'E' {
-                    let final_period = t == periods - 1;
-                    rust::policies::esr::calculate_esr(
-                        &policy_constructor,
-                        &one_step_ahead_expectations,
-                        state.1,
-                        state.2,
-                        base_stock_policy.1,
-                        base_stock_policy.2,
-                        final_period,
-                    )
-                } else {
-                    panic!("Transhipment policy not recognised");
-                };
-                // println!("State: {:?}, Transhipment action: {:?}", state, transhipment_action);
-                if ordering_policy == 'R' {
-                    ordering_action = rust::policies::base_stock::regular_base_stock(
-                        (
-                            state.0,
-                            state.1 - transhipment_action.0 + transhipment_action.1,
-                            state.2 - transhipment_action.1 + transhipment_action.0,
-                        ),
-                        base_stock_policy.0,
-                        (base_stock_policy.1, base_stock_policy.2),
-                        None
-                    );
-                    
-                } else if ordering_policy == 'C' {
-                    ordering_action = rust::policies::base_stock::regular_base_stock(
-                        (
-                            state.0,
-                            state.1 - transhipment_action.0 + transhipment_action.1,
-                            state.2 - transhipment_action.1 + transhipment_action.0,
-                        ),
-                        base_stock_policy.0,
-                        (base_stock_policy.1, base_stock_policy.2),
-                        order_cap
-                    );
-                } else {
-                    panic!("Ordering policy not recognised");
-                }
-            }
-
-            let action = (
-                ordering_action.0,
-                ordering_action.1,
-                ordering_action.2,
-                transhipment_action.0,
-                transhipment_action.1,
-            );
-
-            // Calculate the value function
-            let v_t_x = rust::value_function::value_function_pol_eval(
-                &policy_constructor,
-                state,
-                &v_plus_1_hm,
-                action,
-                &store_expectation,
-                &warehouse_expectation,
-            );
-            v.insert(state, v_t_x);
-            // Store the optimal policy
-            optimal_pol.insert((t, state.0, state.1, state.2), action);
-        });
-        //println!();
+    // Value function for every period (terminal period included), only filled when all_periods is set
+    let mut v_all: HashMap<(usize, usize, usize, usize), f64> = HashMap::new();
+    if all_periods {
+        rust::value_function::record_period_values(
+            &mut v_all,
+            periods + 1,
+            v.iter().map(|entry| (*entry.key(), *entry.value())),
+        );
+    }
+    // Progress is only reported when a callback is given
+    let progress = rust::progress::ProgressTracker::from_py(
+        progress,
+        periods,
+        policy_constructor.max_wh * policy_constructor.max_sa * policy_constructor.max_sb,
+    );
+    // Solve with the GIL released so other Python threads keep running
+    let optimal_pol = py.allow_threads(|| {
+        // Precompute anything the heuristic needs (e.g. one step ahead expectations)
+        policy.setup(periods);
+        rust::evaluation::evaluate_policy(
+            &policy_constructor,
+            policy.as_ref(),
+            periods,
+            &v,
+            all_periods.then_some(&mut v_all),
+            &progress,
+        )
+    })?;
+    if as_array {
+        let pol_array = rust::arrays::policy_array(
+            &policy_constructor,
+            periods,
+            optimal_pol.iter().map(|entry| (*entry.key(), *entry.value())),
+        );
+        let v_array = rust::arrays::value_function_pyarray(
+            py,
+            &policy_constructor,
+            periods,
+            all_periods,
+            v.iter().map(|entry| (*entry.key(), *entry.value())),
+            &v_all,
+        );
+        return Ok((pol_array.into_pyarray_bound(py), v_array).into_py(py));
     }
     let optimal_pol_hm = optimal_pol
         .clone()
         .into_iter()
         .collect::<HashMap<(usize, usize, usize, usize), (usize, usize, usize, usize, usize)>>();
+    if all_periods {
+        return Ok((optimal_pol_hm, v_all).into_py(py));
+    }
     let v_hm = v
         .clone()
         .into_iter()
         .collect::<HashMap<(usize, usize, usize), f64>>();
-    Ok((optimal_pol_hm, v_hm))
+    Ok((optimal_pol_hm, v_hm).into_py(py))
 }
 
 // Policy evaluation of the optimal action
 #[pyfunction]
-#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, optimal_actions,num_cores=4, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999))]
+#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, optimal_actions,num_cores=4, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None, dfw_thresholds=None, all_periods=false, terminal_unit_cost=None, terminal_values=None, progress=None, as_array=false))]
 fn policy_evaluation_par_opt(
+    py: Python<'_>,
     periods: usize,
     sa_demand_param_one: f64,
     sb_demand_param_one: f64,
-    h_s: f64,
+    h_s: rust::policy_contructor::PerStore,
     h_w: f64,
-    c_u_s: f64,
-    c_p: f64,
-    c_ts: f64,
-    optimal_actions: HashMap<(usize, usize, usize, usize), (usize, usize, usize, usize, usize)>,
+    c_u_s: rust::policy_contructor::PerStore,
+    c_p: rust::policy_contructor::PerStore,
+    c_ts: rust::policy_contructor::PerDirection,
+    optimal_actions: rust::arrays::PolicyInput<'_>,
     num_cores: Option<usize>,
-    p: Option<f64>,
+    p: Option<rust::policy_contructor::PerStore>,
     sa_demand_param_two: Option<f64>,
     sb_demand_param_two: Option<f64>,
     distribution: Option<char>,
@@ -267,29 +213,40 @@ fn policy_evaluation_par_opt(
     max_sa: Option<usize>,
     max_sb: Option<usize>,
     gamma: Option<f64>,
-) -> PyResult<HashMap<(usize, usize, usize), f64>> {
+    c_ts_fixed: f64,
+    dfw_order: Option<rust::policy_contructor::DfwOrder>,
+    dfw_threshold: usize,
+    substitution: Option<rust::policy_contructor::PerStore>,
+    online_demand_param_one: Option<f64>,
+    online_demand_param_two: Option<f64>,
+    c_u_w: f64,
+    c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
+    dfw_thresholds: Option<rust::arrays::DfwThresholdTable>,
+    all_periods: bool,
+    terminal_unit_cost: Option<(f64, f64, f64)>,
+    terminal_values: Option<HashMap<(usize, usize, usize), f64>>,
+    progress: Option<PyObject>,
+    as_array: bool,
+) -> PyResult<PyObject> {
     // Stores all the infrastructure for the parameters in the optimal policy
-    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(
-        sa_demand_param_one,
-        sb_demand_param_one,
-        h_s,
-        h_w,
-        c_u_s,
-        c_p,
-        c_ts,
-        0, // Optinal doesn't need base-stock in the policy construcutor
-        0, // Optinal doesn't need base-stock in the policy construcutor
-        p,
-        sa_demand_param_two,
-        sb_demand_param_two,
-        distribution,
-        max_wh,
-        max_sa,
-        max_sb,
-        gamma,
+    let model = rust::policy_contructor::ModelParams::new(
+        sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+        sa_demand_param_two, sb_demand_param_two, distribution, max_wh, max_sa, max_sb, gamma, dfw_order,
+        dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w, c_ts_emergency,
     );
-    let store_expectation = policy_constructor.expectation_all_stores();
-    let warehouse_expectation = policy_constructor.expectation_all_warehouse();
+    // Optimal doesn't need base-stock in the policy constructor
+    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(&model, (0, 0));
+    // Actions can be given as a dict, an array of shape (periods, max_wh, max_sa, max_sb, 5)
+    // or a callable f(t, wh, sa, sb) that is called once per period and state
+    let optimal_actions = optimal_actions.into_table(&policy_constructor, periods)?;
+    // DFW thresholds from optimal_policy_par with optimise_dfw_threshold replace the fixed dfw_threshold
+    if let Some(dfw_thresholds) = &dfw_thresholds {
+        rust::arrays::validate_dfw_thresholds(&policy_constructor, periods, dfw_thresholds)?;
+    }
+    let policy = rust::policies::TablePolicy {
+        actions: &optimal_actions,
+        dfw_thresholds: dfw_thresholds.as_ref(),
+    };
 
     // Create the thread pool
     rayon::ThreadPoolBuilder::new()
@@ -297,68 +254,74 @@ fn policy_evaluation_par_opt(
         .build()
         .unwrap();
 
-    // Load in terminal cost (assume zero for now)
-    let v: DashMap<(usize, usize, usize), f64> =
-        rust::value_function::terminal_cost(&policy_constructor, None);
-    // Iterate through periods
-
-    for t in (1..periods).rev() {
-        // Save previous iteration (v_t+1)
-        let v_plus_1 = v.clone();
-        //v.clear(); // Reset V to repopulate
-
-        // Iterate through all states
-        let state_space: Vec<(usize, usize, usize)> = policy_constructor
-            .construct_state_space_iterator()
-            .collect();
-        //println!("Length of state space: {:?}",state_space.len());
-        state_space.clone().par_iter().for_each(|state| {
-            //println!("Testing state {:?} out of {:?}. Current: {:?}",state, state_space.len(), state);
-            let state = (state.0, state.1, state.2);
-            let v_plus_1_hm = v_plus_1
-                .clone()
-                .into_iter()
-                .collect::<HashMap<(usize, usize, usize), f64>>();
-
-            let action = optimal_actions
-                .get(&(t, state.0, state.1, state.2))
-                .unwrap();
-
-            // Calculate the value function
-            let v_t_x = rust::value_function::value_function_pol_eval(
-                &policy_constructor,
-                state,
-                &v_plus_1_hm,
-                *action,
-                &store_expectation,
-                &warehouse_expectation,
-            );
-            
-            v.insert(state, v_t_x);
-        });
-        
+    // Load in terminal cost (zero unless a per-unit cost or value table is given)
+    let v: DashMap<(usize, usize, usize), f64> = rust::value_function::terminal_cost(
+        &policy_constructor,
+        terminal_unit_cost,
+        terminal_values.as_ref(),
+    )?;
+    // Value function for every period (terminal period included), only filled when all_periods is set
+    let mut v_all: HashMap<(usize, usize, usize, usize), f64> = HashMap::new();
+    if all_periods {
+        rust::value_function::record_period_values(
+            &mut v_all,
+            periods + 1,
+            v.iter().map(|entry| (*entry.key(), *entry.value())),
+        );
+    }
+    // Progress is only reported when a callback is given
+    let progress = rust::progress::ProgressTracker::from_py(
+        progress,
+        periods,
+        policy_constructor.max_wh * policy_constructor.max_sa * policy_constructor.max_sb,
+    );
+    // Solve with the GIL released so other Python threads keep running
+    py.allow_threads(|| {
+        rust::evaluation::evaluate_policy(
+            &policy_constructor,
+            &policy,
+            periods,
+            &v,
+            all_periods.then_some(&mut v_all),
+            &progress,
+        )
+    })?;
+    if as_array {
+        let v_array = rust::arrays::value_function_pyarray(
+            py,
+            &policy_constructor,
+            periods,
+            all_periods,
+            v.iter().map(|entry| (*entry.key(), *entry.value())),
+            &v_all,
+        );
+        return Ok(v_array.into_py(py));
+    }
+    if all_periods {
+        return Ok(v_all.into_py(py));
     }
     let v_hm = v
         .clone()
         .into_iter()
         .collect::<HashMap<(usize, usize, usize), f64>>();
-    Ok(v_hm)
+    Ok(v_hm.into_py(py))
 }
 
 // Optimal Policy
 #[pyfunction]
-#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, num_cores=4, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999))]
+#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, num_cores=4, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None, optimise_dfw_threshold=false, all_periods=false, terminal_unit_cost=None, terminal_values=None, progress=None, as_array=false))]
 fn optimal_policy_par(
+    py: Python<'_>,
     periods: usize,
     sa_demand_param_one: f64,
     sb_demand_param_one: f64,
-    h_s: f64,
+    h_s: rust::policy_contructor::PerStore,
     h_w: f64,
-    c_u_s: f64,
-    c_p: f64,
-    c_ts: f64,
+    c_u_s: rust::policy_contructor::PerStore,
+    c_p: rust::policy_contructor::PerStore,
+    c_ts: rust::policy_contructor::PerDirection,
     num_cores: Option<usize>,
-    p: Option<f64>,
+    p: Option<rust::policy_contructor::PerStore>,
     sa_demand_param_two: Option<f64>,
     sb_demand_param_two: Option<f64>,
     distribution: Option<char>,
@@ -366,105 +329,127 @@ fn optimal_policy_par(
     max_sa: Option<usize>,
     max_sb: Option<usize>,
     gamma: Option<f64>,
-) -> PyResult<(
-    HashMap<(usize, usize, usize, usize), (usize, usize, usize, usize, usize)>,
-    HashMap<(usize, usize, usize), f64>,
-)> {
+    c_ts_fixed: f64,
+    dfw_order: Option<rust::policy_contructor::DfwOrder>,
+    dfw_threshold: usize,
+    substitution: Option<rust::policy_contructor::PerStore>,
+    online_demand_param_one: Option<f64>,
+    online_demand_param_two: Option<f64>,
+    c_u_w: f64,
+    c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
+    optimise_dfw_threshold: bool,
+    all_periods: bool,
+    terminal_unit_cost: Option<(f64, f64, f64)>,
+    terminal_values: Option<HashMap<(usize, usize, usize), f64>>,
+    progress: Option<PyObject>,
+    as_array: bool,
+) -> PyResult<PyObject> {
     // Stores all the infrastructure for the parameters in the optimal policy
-    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(
-        sa_demand_param_one,
-        sb_demand_param_one,
-        h_s,
-        h_w,
-        c_u_s,
-        c_p,
-        c_ts,
-        0, // Optinal doesn't need base-stock in the policy construcutor
-        0, // Optinal doesn't need base-stock in the policy construcutor
-        p,
-        sa_demand_param_two,
-        sb_demand_param_two,
-        distribution,
-        max_wh,
-        max_sa,
-        max_sb,
-        gamma,
+    let model = rust::policy_contructor::ModelParams::new(
+        sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+        sa_demand_param_two, sb_demand_param_two, distribution, max_wh, max_sa, max_sb, gamma, dfw_order,
+        dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w, c_ts_emergency,
     );
-    let store_expectation = policy_constructor.expectation_all_stores();
-    let warehouse_expectation = policy_constructor.expectation_all_warehouse();
-    let action_space = policy_constructor.construct_action_space();
-
+    // Optimal doesn't need base-stock in the policy constructor
+    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(&model, (0, 0));
     // Create the thread pool
     rayon::ThreadPoolBuilder::new()
         .num_threads(num_cores.unwrap_or(4))
         .build()
         .unwrap();
 
-    // Load in terminal cost (assume zero for now)
-    let v: DashMap<(usize, usize, usize), f64> =
-        rust::value_function::terminal_cost(&policy_constructor, None);
-    let optimal_pol: DashMap<(usize, usize, usize, usize), (usize, usize, usize, usize, usize)> =
-        DashMap::new();
-    // Iterate through periods
-    for t in (1..periods).rev() {
-        println!("Period: {:?}", t);
-        // Save previous iteration (v_t+1)
-        let v_plus_1 = v.clone();
-        //v.clear(); // Reset V to repopulate
-
-        // Iterate through all states
-        let state_space: Vec<(usize, usize, usize)> = policy_constructor
-            .construct_state_space_iterator()
-            .collect();
-        //println!("Length of state space: {:?}",state_space.len());
-
-        state_space.clone().par_iter().for_each(|state| {
-            //println!("Testing state {:?} out of {:?}. Current: {:?}",state, state_space.len(), state);
-            let state = (state.0, state.1, state.2);
-            let v_plus_1_hm = v_plus_1
-                .clone()
-                .into_iter()
-                .collect::<HashMap<(usize, usize, usize), f64>>();
-            // Calculate the value function
-            let (action, v_t_x) = rust::value_function::value_function_optimal_pol(
-                &policy_constructor,
-                state,
-                &v_plus_1_hm,
-                &action_space[&state],
-                &store_expectation,
-                &warehouse_expectation,
-            );
-            // Update the value function
-
-            v.insert(state, v_t_x);
-            // Store the optimal policy
-            optimal_pol.insert((t, state.0, state.1, state.2), action);
-        });
-
+    // Load in terminal cost (zero unless a per-unit cost or value table is given)
+    let v: DashMap<(usize, usize, usize), f64> = rust::value_function::terminal_cost(
+        &policy_constructor,
+        terminal_unit_cost,
+        terminal_values.as_ref(),
+    )?;
+    // Value function for every period (terminal period included), only filled when all_periods is set
+    let mut v_all: HashMap<(usize, usize, usize, usize), f64> = HashMap::new();
+    if all_periods {
+        rust::value_function::record_period_values(
+            &mut v_all,
+            periods + 1,
+            v.iter().map(|entry| (*entry.key(), *entry.value())),
+        );
+    }
+    // Progress is only reported when a callback is given
+    let progress = rust::progress::ProgressTracker::from_py(
+        progress,
+        periods,
+        policy_constructor.max_wh * policy_constructor.max_sa * policy_constructor.max_sb,
+    );
+    // DFW thresholds chosen by the DP, only filled when optimise_dfw_threshold is set
+    let dfw_thresholds = rust::optimal::DfwThresholds::new();
+    // Solve with the GIL released so other Python threads keep running
+    let optimal_pol = py.allow_threads(|| {
+        rust::optimal::solve_optimal_policy(
+            &policy_constructor,
+            periods,
+            &v,
+            all_periods.then_some(&mut v_all),
+            optimise_dfw_threshold.then_some(&dfw_thresholds),
+            &progress,
+        )
+    })?;
+    // Returned after the policy and value function when the DP chose them
+    let with_thresholds = |pol: PyObject, v_out: PyObject| -> PyObject {
+        if optimise_dfw_threshold {
+            let dfw_thresholds_hm = dfw_thresholds
+                .iter()
+                .map(|entry| (*entry.key(), *entry.value()))
+                .collect::<HashMap<(usize, usize, usize, usize), usize>>();
+            (pol, v_out, dfw_thresholds_hm).into_py(py)
+        } else {
+            (pol, v_out).into_py(py)
+        }
+    };
+    if as_array {
+        let pol_array = rust::arrays::policy_array(
+            &policy_constructor,
+            periods,
+            optimal_pol.iter().map(|entry| (*entry.key(), *entry.value())),
+        );
+        let v_array = rust::arrays::value_function_pyarray(
+            py,
+            &policy_constructor,
+            periods,
+            all_periods,
+            v.iter().map(|entry| (*entry.key(), *entry.value())),
+            &v_all,
+        );
+        return Ok(with_thresholds(
+            pol_array.into_pyarray_bound(py).into_py(py),
+            v_array.into_py(py),
+        ));
     }
     let optimal_pol_hm = optimal_pol
         .clone()
         .into_iter()
         .collect::<HashMap<(usize, usize, usize, usize), (usize, usize, usize, usize, usize)>>();
+    if all_periods {
+        return Ok(with_thresholds(optimal_pol_hm.into_py(py), v_all.into_py(py)));
+    }
     let v_hm = v
         .clone()
         .into_iter()
         .collect::<HashMap<(usize, usize, usize), f64>>();
-    Ok((optimal_pol_hm, v_hm))
+    Ok(with_thresholds(optimal_pol_hm.into_py(py), v_hm.into_py(py)))
 }
 
 #[pyfunction]
-#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999))]
+#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None, optimise_dfw_threshold=false, all_periods=false, terminal_unit_cost=None, terminal_values=None, progress=None, as_array=false))]
 fn optimal_policy(
+    py: Python<'_>,
     periods: usize,
     sa_demand_param_one: f64,
     sb_demand_param_one: f64,
-    h_s: f64,
+    h_s: rust::policy_contructor::PerStore,
     h_w: f64,
-    c_u_s: f64,
-    c_p: f64,
-    c_ts: f64,
-    p: Option<f64>,
+    c_u_s: rust::policy_contructor::PerStore,
+    c_p: rust::policy_contructor::PerStore,
+    c_ts: rust::policy_contructor::PerDirection,
+    p: Option<rust::policy_contructor::PerStore>,
     sa_demand_param_two: Option<f64>,
     sb_demand_param_two: Option<f64>,
     distribution: Option<char>,
@@ -472,92 +457,170 @@ fn optimal_policy(
     max_sa: Option<usize>,
     max_sb: Option<usize>,
     gamma: Option<f64>,
-) -> PyResult<(
-    HashMap<(usize, usize, usize, usize), (usize, usize, usize, usize, usize)>,
-    HashMap<(usize, usize, usize), f64>,
-)> {
+    c_ts_fixed: f64,
+    dfw_order: Option<rust::policy_contructor::DfwOrder>,
+    dfw_threshold: usize,
+    substitution: Option<rust::policy_contructor::PerStore>,
+    online_demand_param_one: Option<f64>,
+    online_demand_param_two: Option<f64>,
+    c_u_w: f64,
+    c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
+    optimise_dfw_threshold: bool,
+    all_periods: bool,
+    terminal_unit_cost: Option<(f64, f64, f64)>,
+    terminal_values: Option<HashMap<(usize, usize, usize), f64>>,
+    progress: Option<PyObject>,
+    as_array: bool,
+) -> PyResult<PyObject> {
     // Stores all the infrastructure for the parameters in the optimal policy
-    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(
-        sa_demand_param_one,
-        sb_demand_param_one,
-        h_s,
-        h_w,
-        c_u_s,
-        c_p,
-        c_ts,
-        0, // Optinal doesn't need base-stock in the policy construcutor
-        0, // Optinal doesn't need base-stock in the policy construcutor
-        p,
-        sa_demand_param_two,
-        sb_demand_param_two,
-        distribution,
-        max_wh,
-        max_sa,
-        max_sb,
-        gamma,
+    let model = rust::policy_contructor::ModelParams::new(
+        sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+        sa_demand_param_two, sb_demand_param_two, distribution, max_wh, max_sa, max_sb, gamma, dfw_order,
+        dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w, c_ts_emergency,
     );
+    // Optimal doesn't need base-stock in the policy constructor
+    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(&model, (0, 0));
     let store_expectation = policy_constructor.expectation_all_stores();
     let warehouse_expectation = policy_constructor.expectation_all_warehouse();
+    let rationed_expectation = if optimise_dfw_threshold {
+        rust::value_function::rationed_expectations(&policy_constructor)?
+    } else {
+        HashMap::new()
+    };
     let action_space = policy_constructor.construct_action_space();
 
-    // Load in terminal cost (assume zero for now)
-    let mut v: HashMap<(usize, usize, usize), f64> =
-        rust::value_function::terminal_cost(&policy_constructor, None)
-            .into_iter()
-            .collect::<HashMap<(usize, usize, usize), f64>>();
+    // Load in terminal cost (zero unless a per-unit cost or value table is given)
+    let mut v: HashMap<(usize, usize, usize), f64> = rust::value_function::terminal_cost(
+        &policy_constructor,
+        terminal_unit_cost,
+        terminal_values.as_ref(),
+    )?
+    .into_iter()
+    .collect::<HashMap<(usize, usize, usize), f64>>();
+    // Value function for every period (terminal period included), only filled when all_periods is set
+    let mut v_all: HashMap<(usize, usize, usize, usize), f64> = HashMap::new();
+    if all_periods {
+        rust::value_function::record_period_values(
+            &mut v_all,
+            periods + 1,
+            v.iter().map(|(state, value)| (*state, *value)),
+        );
+    }
     let mut optimal_pol: HashMap<
         (usize, usize, usize, usize),
         (usize, usize, usize, usize, usize),
     > = HashMap::new();
-    // Iterate through periods
-    for t in (1..periods).rev() {
-        println!("Period: {:?}", t);
-        // Save previous iteration (v_t+1)
-        let v_plus_1 = v.clone();
-        v.clear(); // Reset V to repopulate
-
-        // Iterate through all states
-        for (index, state) in policy_constructor
-            .construct_state_space_iterator()
-            .enumerate()
-        {
-            print!(
-                "\rTesting state {:?} out of {:?}. Current: {:?}",
-                index,
-                policy_constructor.max_wh * policy_constructor.max_sa * policy_constructor.max_sb,
-                state
-            );
-            let state = (state.0, state.1, state.2);
-            // Calculate the value function
-            let (action, v_t_x) = rust::value_function::value_function_optimal_pol(
-                &policy_constructor,
-                state,
-                &v_plus_1,
-                &action_space[&state],
-                &store_expectation,
-                &warehouse_expectation,
-            );
-            // Update the value function
-            v.insert(state, v_t_x);
-            // Store the optimal policy
-            optimal_pol.insert((t, state.0, state.1, state.2), action);
+    // DFW thresholds chosen by the DP, only filled when optimise_dfw_threshold is set
+    let mut dfw_thresholds: rust::arrays::DfwThresholdTable = HashMap::new();
+    // Progress is only reported when a callback is given
+    let progress = rust::progress::ProgressTracker::from_py(
+        progress,
+        periods,
+        policy_constructor.max_wh * policy_constructor.max_sa * policy_constructor.max_sb,
+    );
+    // Solve with the GIL released so other Python threads keep running
+    py.allow_threads(|| -> PyResult<()> {
+        // Iterate through periods
+        for t in (1..=periods).rev() {
+            // Save previous iteration (v_t+1)
+            let v_plus_1 = v.clone();
+            v.clear(); // Reset V to repopulate
+
+            // Iterate through all states
+            for (index, state) in policy_constructor
+                .construct_state_space_iterator()
+                .enumerate()
+            {
+                let state = (state.0, state.1, state.2);
+                // Calculate the value function
+                let (action, v_t_x) = if optimise_dfw_threshold {
+                    let (action, dfw_threshold, v_t_x) =
+                        rust::value_function::value_function_optimal_pol_rationed(
+                            &policy_constructor,
+                            state,
+                            &v_plus_1,
+                            &action_space[&state],
+                            &rationed_expectation,
+                        );
+                    dfw_thresholds.insert((t, state.0, state.1, state.2), dfw_threshold);
+                    (action, v_t_x)
+                } else {
+                    rust::value_function::value_function_optimal_pol(
+                        &policy_constructor,
+                        state,
+                        &v_plus_1,
+                        &action_space[&state],
+                        &store_expectation,
+                        &warehouse_expectation,
+                    )
+                };
+                // Update the value function
+                v.insert(state, v_t_x);
+                // Store the optimal policy
+                optimal_pol.insert((t, state.0, state.1, state.2), action);
+                // Poll for Ctrl-C and report progress every few states
+                if (index + 1) % rust::interrupt::SIGNAL_CHECK_INTERVAL == 0 {
+                    rust::interrupt::check_signals()?;
+                    progress.report(t, index + 1)?;
+                }
+            }
+            // Poll for Ctrl-C and report progress between periods
+            rust::interrupt::check_signals()?;
+            progress.report(t, v.len())?;
+            if all_periods {
+                rust::value_function::record_period_values(
+                    &mut v_all,
+                    t,
+                    v.iter().map(|(state, value)| (*state, *value)),
+                );
+            }
+        }
+        Ok(())
+    })?;
+    // Returned after the policy and value function when the DP chose them, as in optimal_policy_par
+    let with_thresholds = |pol: PyObject, v_out: PyObject| -> PyObject {
+        if optimise_dfw_threshold {
+            (pol, v_out, dfw_thresholds.clone()).into_py(py)
+        } else {
+            (pol, v_out).into_py(py)
         }
-        println!();
+    };
+    if as_array {
+        let pol_array = rust::arrays::policy_array(
+            &policy_constructor,
+            periods,
+            optimal_pol.iter().map(|(key, action)| (*key, *action)),
+        );
+        let v_array = rust::arrays::value_function_pyarray(
+            py,
+            &policy_constructor,
+            periods,
+            all_periods,
+            v.iter().map(|(state, value)| (*state, *value)),
+            &v_all,
+        );
+        return Ok(with_thresholds(
+            pol_array.into_pyarray_bound(py).into_py(py),
+            v_array.into_py(py),
+        ));
     }
-    Ok((optimal_pol, v))
+    if all_periods {
+        return Ok(with_thresholds(optimal_pol.into_py(py), v_all.into_py(py)));
+    }
+    Ok(with_thresholds(optimal_pol.into_py(py), v.into_py(py)))
 }
 
 #[pyfunction]
-#[pyo3(signature = (sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999))]
+#[pyo3(signature = (sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None))]
 fn warehouse_store_expectations_py(
     sa_demand_param_one: f64,
     sb_demand_param_one: f64,
-    h_s: f64,
+    h_s: rust::policy_contructor::PerStore,
     h_w: f64,
-    c_u_s: f64,
-    c_p: f64,
-    c_ts: f64,
-    p: Option<f64>,
+    c_u_s: rust::policy_contructor::PerStore,
+    c_p: rust::policy_contructor::PerStore,
+    c_ts: rust::policy_contructor::PerDirection,
+    p: Option<rust::policy_contructor::PerStore>,
     sa_demand_param_two: Option<f64>,
     sb_demand_param_two: Option<f64>,
     distribution: Option<char>,
@@ -565,46 +628,42 @@ fn warehouse_store_expectations_py(
     max_sa: Option<usize>,
     max_sb: Option<usize>,
     gamma: Option<f64>,
+    c_ts_fixed: f64,
+    dfw_order: Option<rust::policy_contructor::DfwOrder>,
+    dfw_threshold: usize,
+    substitution: Option<rust::policy_contructor::PerStore>,
+    online_demand_param_one: Option<f64>,
+    online_demand_param_two: Option<f64>,
+    c_u_w: f64,
+    c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
 ) -> PyResult<(
     HashMap<(usize, usize, usize), f64>,
     HashMap<(usize, usize, usize), f64>,
 )> {
     // Stores all the infrastructure for the parameters in the optimal policy
-    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(
-        sa_demand_param_one,
-        sb_demand_param_one,
-        h_s,
-        h_w,
-        c_u_s,
-        c_p,
-        c_ts,
-        0, // Optinal doesn't need base-stock in the policy construcutor
-        0, // Optinal doesn't need base-stock in the policy construcutor
-        p,
-        sa_demand_param_two,
-        sb_demand_param_two,
-        distribution,
-        max_wh,
-        max_sa,
-        max_sb,
-        gamma,
+    let model = rust::policy_contructor::ModelParams::new(
+        sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+        sa_demand_param_two, sb_demand_param_two, distribution, max_wh, max_sa, max_sb, gamma, dfw_order,
+        dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w, c_ts_emergency,
     );
+    // Optimal doesn't need base-stock in the policy constructor
+    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(&model, (0, 0));
     let store_expectation = policy_constructor.expectation_all_stores();
     let warehouse_expectation = policy_constructor.expectation_all_warehouse();
     Ok((store_expectation, warehouse_expectation))
 }
 
 #[pyfunction]
-#[pyo3(signature = (sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999))]
+#[pyo3(signature = (sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None))]
 fn action_space_visualiser(
     sa_demand_param_one: f64,
     sb_demand_param_one: f64,
-    h_s: f64,
+    h_s: rust::policy_contructor::PerStore,
     h_w: f64,
-    c_u_s: f64,
-    c_p: f64,
-    c_ts: f64,
-    p: Option<f64>,
+    c_u_s: rust::policy_contructor::PerStore,
+    c_p: rust::policy_contructor::PerStore,
+    c_ts: rust::policy_contructor::PerDirection,
+    p: Option<rust::policy_contructor::PerStore>,
     sa_demand_param_two: Option<f64>,
     sb_demand_param_two: Option<f64>,
     distribution: Option<char>,
@@ -612,32 +671,542 @@ fn action_space_visualiser(
     max_sa: Option<usize>,
     max_sb: Option<usize>,
     gamma: Option<f64>,
+    c_ts_fixed: f64,
+    dfw_order: Option<rust::policy_contructor::DfwOrder>,
+    dfw_threshold: usize,
+    substitution: Option<rust::policy_contructor::PerStore>,
+    online_demand_param_one: Option<f64>,
+    online_demand_param_two: Option<f64>,
+    c_u_w: f64,
+    c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
 ) -> PyResult<
     HashMap<(usize, usize, usize), Vec<(usize, usize, usize, usize, usize)>>
 >{
-     // Stores all the infrastructure for the parameters in the optimal policy
-     let policy_constructor = rust::policy_contructor::OptimalPolicy::new(
-        sa_demand_param_one,
-        sb_demand_param_one,
-        h_s,
-        h_w,
-        c_u_s,
-        c_p,
-        c_ts,
-        0, // Optinal doesn't need base-stock in the policy construcutor
-        0, // Optinal doesn't need base-stock in the policy construcutor
-        p,
-        sa_demand_param_two,
-        sb_demand_param_two,
-        distribution,
-        max_wh,
-        max_sa,
-        max_sb,
-        gamma,
+    // Stores all the infrastructure for the parameters in the optimal policy
+    let model = rust::policy_contructor::ModelParams::new(
+        sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+        sa_demand_param_two, sb_demand_param_two, distribution, max_wh, max_sa, max_sb, gamma, dfw_order,
+        dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w, c_ts_emergency,
     );
+    // Optimal doesn't need base-stock in the policy constructor
+    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(&model, (0, 0));
     let action_space = policy_constructor.construct_action_space();
     Ok(action_space)
 }
+// Q-function for a single state: every feasible action with its immediate cost, discounted future cost and total,
+// cheapest first. next_values is the value function of period + 1, or the output of all_periods=True for every period.
+// Actions within epsilon of the cheapest are flagged as near ties.
+#[pyfunction]
+#[pyo3(signature = (period, state, next_values, sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None, dfw_thresholds=None, epsilon=1e-6))]
+fn q_function(
+    py: Python<'_>,
+    period: usize,
+    state: (usize, usize, usize),
+    next_values: rust::arrays::ValueFunctionInput<'_>,
+    sa_demand_param_one: f64,
+    sb_demand_param_one: f64,
+    h_s: rust::policy_contructor::PerStore,
+    h_w: f64,
+    c_u_s: rust::policy_contructor::PerStore,
+    c_p: rust::policy_contructor::PerStore,
+    c_ts: rust::policy_contructor::PerDirection,
+    p: Option<rust::policy_contructor::PerStore>,
+    sa_demand_param_two: Option<f64>,
+    sb_demand_param_two: Option<f64>,
+    distribution: Option<char>,
+    max_wh: Option<usize>,
+    max_sa: Option<usize>,
+    max_sb: Option<usize>,
+    gamma: Option<f64>,
+    c_ts_fixed: f64,
+    dfw_order: Option<rust::policy_contructor::DfwOrder>,
+    dfw_threshold: usize,
+    substitution: Option<rust::policy_contructor::PerStore>,
+    online_demand_param_one: Option<f64>,
+    online_demand_param_two: Option<f64>,
+    c_u_w: f64,
+    c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
+    dfw_thresholds: Option<rust::arrays::DfwThresholdTable>,
+    epsilon: f64,
+) -> PyResult<Vec<PyObject>> {
+    // Stores all the infrastructure for the parameters in the optimal policy
+    let model = rust::policy_contructor::ModelParams::new(
+        sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+        sa_demand_param_two, sb_demand_param_two, distribution, max_wh, max_sa, max_sb, gamma, dfw_order,
+        dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w, c_ts_emergency,
+    );
+    // Optimal doesn't need base-stock in the policy constructor
+    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(&model, (0, 0));
+    if state.0 >= policy_constructor.max_wh
+        || state.1 >= policy_constructor.max_sa
+        || state.2 >= policy_constructor.max_sb
+    {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "State {:?} is outside the state space",
+            state
+        )));
+    }
+    let v_plus_1 = next_values.into_period(&policy_constructor, period + 1)?;
+    // The state's threshold from optimal_policy_par with optimise_dfw_threshold replaces the fixed dfw_threshold
+    let state_dfw_threshold = match &dfw_thresholds {
+        Some(dfw_thresholds) => match dfw_thresholds.get(&(period, state.0, state.1, state.2)) {
+            Some(dfw_threshold) => Some(*dfw_threshold),
+            None => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "DFW thresholds are missing period {} state {:?}",
+                    period, state
+                )))
+            }
+        },
+        None => None,
+    };
+    let store_expectation = policy_constructor.expectation_all_stores();
+    let warehouse_expectation = policy_constructor.expectation_all_warehouse();
+    let q = py.allow_threads(|| {
+        rust::value_function::q_values(
+            &policy_constructor,
+            state,
+            &v_plus_1,
+            &store_expectation,
+            &warehouse_expectation,
+            state_dfw_threshold,
+        )
+    });
+    let best = q[0].1 .2;
+    q.into_iter()
+        .map(|(action, (im_cost, fut_cost, total_cost))| {
+            let row = pyo3::types::PyDict::new_bound(py);
+            row.set_item("action", action)?;
+            row.set_item("immediate_cost", im_cost)?;
+            row.set_item("future_cost", fut_cost)?;
+            row.set_item("total_cost", total_cost)?;
+            row.set_item("gap", total_cost - best)?;
+            row.set_item("optimal", total_cost == best)?;
+            row.set_item("near_tie", total_cost != best && total_cost - best <= epsilon)?;
+            Ok(row.into_py(py))
+        })
+        .collect()
+}
+
+// Truncation diagnostics for a policy, e.g. the one returned by optimal_policy_par.
+// Pushes the state distribution forward from initial_state and reports how often states sit on the bounds,
+// how often actions are cut off by the bounds and the demand probability lost beyond D_MAX.
+// Larger bounds are recommended when any of these exceed threshold.
+#[pyfunction]
+#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, optimal_actions, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None, dfw_thresholds=None, initial_state=(0,0,0), threshold=0.01))]
+fn truncation_diagnostics(
+    py: Python<'_>,
+    periods: usize,
+    sa_demand_param_one: f64,
+    sb_demand_param_one: f64,
+    h_s: rust::policy_contructor::PerStore,
+    h_w: f64,
+    c_u_s: rust::policy_contructor::PerStore,
+    c_p: rust::policy_contructor::PerStore,
+    c_ts: rust::policy_contructor::PerDirection,
+    optimal_actions: rust::arrays::PolicyInput<'_>,
+    p: Option<rust::policy_contructor::PerStore>,
+    sa_demand_param_two: Option<f64>,
+    sb_demand_param_two: Option<f64>,
+    distribution: Option<char>,
+    max_wh: Option<usize>,
+    max_sa: Option<usize>,
+    max_sb: Option<usize>,
+    gamma: Option<f64>,
+    c_ts_fixed: f64,
+    dfw_order: Option<rust::policy_contructor::DfwOrder>,
+    dfw_threshold: usize,
+    substitution: Option<rust::policy_contructor::PerStore>,
+    online_demand_param_one: Option<f64>,
+    online_demand_param_two: Option<f64>,
+    c_u_w: f64,
+    c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
+    dfw_thresholds: Option<rust::arrays::DfwThresholdTable>,
+    initial_state: (usize, usize, usize),
+    threshold: f64,
+) -> PyResult<PyObject> {
+    // Stores all the infrastructure for the parameters in the optimal policy
+    let model = rust::policy_contructor::ModelParams::new(
+        sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+        sa_demand_param_two, sb_demand_param_two, distribution, max_wh, max_sa, max_sb, gamma, dfw_order,
+        dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w, c_ts_emergency,
+    );
+    // Optimal doesn't need base-stock in the policy constructor
+    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(&model, (0, 0));
+    if initial_state.0 >= policy_constructor.max_wh
+        || initial_state.1 >= policy_constructor.max_sa
+        || initial_state.2 >= policy_constructor.max_sb
+    {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Initial state {:?} is outside the state space",
+            initial_state
+        )));
+    }
+    let optimal_actions = optimal_actions.into_table(&policy_constructor, periods)?;
+    if let Some(dfw_thresholds) = &dfw_thresholds {
+        rust::arrays::validate_dfw_thresholds(&policy_constructor, periods, dfw_thresholds)?;
+    }
+    let policy = rust::policies::TablePolicy {
+        actions: &optimal_actions,
+        dfw_thresholds: dfw_thresholds.as_ref(),
+    };
+    let report = py.allow_threads(|| {
+        rust::diagnostics::truncation_report(
+            &policy_constructor,
+            &policy,
+            periods,
+            initial_state,
+            threshold,
+        )
+    });
+    Ok(report.to_dict(py)?.into_py(py))
+}
+
+// Picks max_wh, max_sa and max_sb instead of guessing them. Starts from newsvendor critical fractiles of two periods
+// of demand plus safety_margin, solves the optimal policy and grows each bound the truncation diagnostics flag
+// by growth_step until every measure is within tolerance or max_iterations solves have been made.
+#[pyfunction]
+#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, num_cores=4, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None, safety_margin=1, growth_step=2, tolerance=0.01, max_iterations=5, initial_state=(0,0,0)))]
+fn auto_bounds(
+    py: Python<'_>,
+    periods: usize,
+    sa_demand_param_one: f64,
+    sb_demand_param_one: f64,
+    h_s: rust::policy_contructor::PerStore,
+    h_w: f64,
+    c_u_s: rust::policy_contructor::PerStore,
+    c_p: rust::policy_contructor::PerStore,
+    c_ts: rust::policy_contructor::PerDirection,
+    num_cores: Option<usize>,
+    p: Option<rust::policy_contructor::PerStore>,
+    sa_demand_param_two: Option<f64>,
+    sb_demand_param_two: Option<f64>,
+    distribution: Option<char>,
+    gamma: Option<f64>,
+    c_ts_fixed: f64,
+    dfw_order: Option<rust::policy_contructor::DfwOrder>,
+    dfw_threshold: usize,
+    substitution: Option<rust::policy_contructor::PerStore>,
+    online_demand_param_one: Option<f64>,
+    online_demand_param_two: Option<f64>,
+    c_u_w: f64,
+    c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
+    safety_margin: usize,
+    growth_step: usize,
+    tolerance: f64,
+    max_iterations: usize,
+    initial_state: (usize, usize, usize),
+) -> PyResult<PyObject> {
+    // Stores all the infrastructure for the parameters in the optimal policy, the bounds are set by auto_bounds
+    let model = rust::policy_contructor::ModelParams::new(
+        sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+        sa_demand_param_two, sb_demand_param_two, distribution, None, None, None, gamma, dfw_order,
+        dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w, c_ts_emergency,
+    );
+    // Optimal doesn't need base-stock in the policy constructor
+    let mut policy_constructor = rust::policy_contructor::OptimalPolicy::new(&model, (0, 0));
+
+    // Create the thread pool
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_cores.unwrap_or(4))
+        .build()
+        .unwrap();
+
+    let result = py.allow_threads(|| {
+        rust::bounds::auto_bounds(
+            &mut policy_constructor,
+            periods,
+            safety_margin,
+            growth_step,
+            tolerance,
+            max_iterations.max(1),
+            initial_state,
+        )
+    })?;
+    let optimal_pol_hm = result
+        .optimal_pol
+        .into_iter()
+        .collect::<HashMap<(usize, usize, usize, usize), (usize, usize, usize, usize, usize)>>();
+    let v_hm = result
+        .v
+        .into_iter()
+        .collect::<HashMap<(usize, usize, usize), f64>>();
+    let out = pyo3::types::PyDict::new_bound(py);
+    out.set_item("bounds", result.bounds)?;
+    out.set_item("initial_bounds", result.initial_bounds)?;
+    out.set_item("iterations", result.iterations)?;
+    out.set_item("converged", result.converged)?;
+    out.set_item("demand_truncated", result.demand_truncated)?;
+    out.set_item("diagnostics", result.report.to_dict(py)?)?;
+    out.set_item("policy", optimal_pol_hm)?;
+    out.set_item("value_function", v_hm)?;
+    Ok(out.into_py(py))
+}
+
+// Long-run metrics of a stationary policy: either a heuristic, chosen with the same codes as policy_evaluation_par_bs,
+// or the slice of a policy table (e.g. from optimal_policy_par) for one period. The period's actions define a Markov chain
+// whose stationary distribution, found by power iteration from initial_state, weights the per-period cost and service.
+// Heuristics are set up for a horizon of periods, so period should not be the last decision period for 'E', 'L' and 'O'.
+#[pyfunction]
+#[pyo3(signature = (periods,sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, optimal_actions=None, period=1, base_stock_vals=(14,7,7), transhipment_policy='N', ordering_policy='R', order_cap=None, tie_seed=None, allocation_rule=None, optimise_warehouse=false, hold_back=None, hold_back_trigger=None, lookahead_steps=1, rollout_base='N', rollout_simulations=None, rollout_seed=0, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None, dfw_thresholds=None, initial_state=(0,0,0), tolerance=1e-10, max_iterations=100000))]
+fn stationary_metrics(
+    py: Python<'_>,
+    periods: usize,
+    sa_demand_param_one: f64,
+    sb_demand_param_one: f64,
+    h_s: rust::policy_contructor::PerStore,
+    h_w: f64,
+    c_u_s: rust::policy_contructor::PerStore,
+    c_p: rust::policy_contructor::PerStore,
+    c_ts: rust::policy_contructor::PerDirection,
+    optimal_actions: Option<rust::arrays::PolicyInput<'_>>,
+    period: usize,
+    base_stock_vals: (usize, usize, usize),
+    transhipment_policy: char,
+    ordering_policy: char,
+    order_cap: Option<(usize, usize)>,
+    tie_seed: Option<u64>,
+    allocation_rule: Option<char>,
+    optimise_warehouse: bool,
+    hold_back: Option<(usize, usize)>,
+    hold_back_trigger: Option<(usize, usize)>,
+    lookahead_steps: usize,
+    rollout_base: char,
+    rollout_simulations: Option<usize>,
+    rollout_seed: u64,
+    p: Option<rust::policy_contructor::PerStore>,
+    sa_demand_param_two: Option<f64>,
+    sb_demand_param_two: Option<f64>,
+    distribution: Option<char>,
+    max_wh: Option<usize>,
+    max_sa: Option<usize>,
+    max_sb: Option<usize>,
+    gamma: Option<f64>,
+    c_ts_fixed: f64,
+    dfw_order: Option<rust::policy_contructor::DfwOrder>,
+    dfw_threshold: usize,
+    substitution: Option<rust::policy_contructor::PerStore>,
+    online_demand_param_one: Option<f64>,
+    online_demand_param_two: Option<f64>,
+    c_u_w: f64,
+    c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
+    dfw_thresholds: Option<rust::arrays::DfwThresholdTable>,
+    initial_state: (usize, usize, usize),
+    tolerance: f64,
+    max_iterations: usize,
+) -> PyResult<PyObject> {
+    // Stores all the infrastructure for the parameters in the optimal policy
+    let model = rust::policy_contructor::ModelParams::new(
+        sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+        sa_demand_param_two, sb_demand_param_two, distribution, max_wh, max_sa, max_sb, gamma, dfw_order,
+        dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w, c_ts_emergency,
+    );
+    let policy_constructor = rust::policy_contructor::OptimalPolicy::new(
+        &model,
+        (base_stock_vals.1, base_stock_vals.2),
+    );
+    if period == 0 || period > periods {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Period {} is not a decision period, those are 1 to {}",
+            period, periods
+        )));
+    }
+    if initial_state.0 >= policy_constructor.max_wh
+        || initial_state.1 >= policy_constructor.max_sa
+        || initial_state.2 >= policy_constructor.max_sb
+    {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "Initial state {:?} is outside the state space",
+            initial_state
+        )));
+    }
+
+    let optimal_actions = match optimal_actions {
+        Some(optimal_actions) => Some(optimal_actions.into_table(&policy_constructor, periods)?),
+        None => None,
+    };
+    // DFW thresholds from optimal_policy_par with optimise_dfw_threshold go with its policy table
+    if let Some(dfw_thresholds) = &dfw_thresholds {
+        if optimal_actions.is_none() {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "dfw_thresholds are only used with optimal_actions",
+            ));
+        }
+        rust::arrays::validate_dfw_thresholds(&policy_constructor, periods, dfw_thresholds)?;
+    }
+    let mut policy: Box<dyn rust::policies::Policy + '_> = match &optimal_actions {
+        Some(actions) => Box::new(rust::policies::TablePolicy {
+            actions,
+            dfw_thresholds: dfw_thresholds.as_ref(),
+        }),
+        None => {
+            let store_a_expectation_mean =
+                rust::distributions::generate_distributions::distribution_mean(
+                    distribution.unwrap_or('P'),
+                    sa_demand_param_one,
+                    sa_demand_param_two,
+                );
+            let store_b_expectation_mean =
+                rust::distributions::generate_distributions::distribution_mean(
+                    distribution.unwrap_or('P'),
+                    sb_demand_param_one,
+                    sb_demand_param_two,
+                );
+            rust::policies::heuristic_policy(
+                &policy_constructor,
+                transhipment_policy,
+                ordering_policy,
+                base_stock_vals,
+                order_cap,
+                (store_a_expectation_mean, store_b_expectation_mean),
+                tie_seed,
+                allocation_rule,
+                optimise_warehouse,
+                hold_back
+                    .zip(hold_back_trigger)
+                    .map(|(hold_back, trigger)| rust::policies::hold_back::HoldBackLevels {
+                        hold_back,
+                        trigger,
+                    }),
+                lookahead_steps,
+                &rust::policies::rollout::RolloutOptions {
+                    base: rollout_base,
+                    simulations: rollout_simulations,
+                    seed: rollout_seed,
+                },
+                // The stationary metrics run without a horizon end
+                None,
+            )?
+        }
+    };
+
+    let report = py.allow_threads(|| {
+        policy.setup(periods);
+        rust::stationary::stationary_report(
+            &policy_constructor,
+            policy.as_ref(),
+            period,
+            initial_state,
+            tolerance,
+            max_iterations,
+        )
+    });
+    let out = pyo3::types::PyDict::new_bound(py);
+    out.set_item("distribution", report.distribution)?;
+    out.set_item("iterations", report.iterations)?;
+    out.set_item("converged", report.converged)?;
+    out.set_item("average_cost", report.average_cost)?;
+    out.set_item("store_fill_rate", report.store_fill_rate)?;
+    out.set_item("fill_rate", report.fill_rate)?;
+    out.set_item("dfw_share", report.dfw_share)?;
+    out.set_item("transhipment_frequency", report.transhipment_frequency)?;
+    out.set_item("mean_transhipment", report.mean_transhipment)?;
+    Ok(out.into_py(py))
+}
+
+// Gym-style environment of the model for training agents, seeded and stepped one period at a time.
+// reset(state) sets the state and step(action) returns (next_state, cost, info) with the cost of the period undiscounted.
+// Takes the same model parameters as the solvers, so results compare directly with optimal_policy_par.
+#[pyclass(name = "Environment")]
+struct PyEnvironment {
+    env: rust::environment::Environment,
+}
+
+#[pymethods]
+impl PyEnvironment {
+    // Takes every model parameter the solvers do, so it has as many arguments as they have
+    #[new]
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (sa_demand_param_one, sb_demand_param_one, h_s,h_w, c_u_s, c_p, c_ts, p=None, sa_demand_param_two=None, sb_demand_param_two=None, distribution=None, max_wh=20, max_sa=10, max_sb=10, gamma=0.999, c_ts_fixed=0.0, dfw_order=None, dfw_threshold=0, substitution=None, online_demand_param_one=None, online_demand_param_two=None, c_u_w=0.0, c_ts_emergency=None, seed=0))]
+    fn new(
+        sa_demand_param_one: f64,
+        sb_demand_param_one: f64,
+        h_s: rust::policy_contructor::PerStore,
+        h_w: f64,
+        c_u_s: rust::policy_contructor::PerStore,
+        c_p: rust::policy_contructor::PerStore,
+        c_ts: rust::policy_contructor::PerDirection,
+        p: Option<rust::policy_contructor::PerStore>,
+        sa_demand_param_two: Option<f64>,
+        sb_demand_param_two: Option<f64>,
+        distribution: Option<char>,
+        max_wh: Option<usize>,
+        max_sa: Option<usize>,
+        max_sb: Option<usize>,
+        gamma: Option<f64>,
+        c_ts_fixed: f64,
+        dfw_order: Option<rust::policy_contructor::DfwOrder>,
+        dfw_threshold: usize,
+        substitution: Option<rust::policy_contructor::PerStore>,
+        online_demand_param_one: Option<f64>,
+        online_demand_param_two: Option<f64>,
+        c_u_w: f64,
+        c_ts_emergency: Option<rust::policy_contructor::PerDirection>,
+        seed: u64,
+    ) -> Self {
+        let model = rust::policy_contructor::ModelParams::new(
+            sa_demand_param_one, sb_demand_param_one, h_s, h_w, c_u_s, c_p, c_ts, c_ts_fixed, p,
+            sa_demand_param_two, sb_demand_param_two, distribution, max_wh, max_sa, max_sb, gamma, dfw_order,
+            dfw_threshold, substitution, online_demand_param_one, online_demand_param_two, c_u_w,
+            c_ts_emergency,
+        );
+        // The environment doesn't need base-stock in the policy constructor
+        let policy_constructor = rust::policy_contructor::OptimalPolicy::new(&model, (0, 0));
+        PyEnvironment {
+            env: rust::environment::Environment::new(policy_constructor, seed),
+        }
+    }
+
+    // Sets the state, and reseeds the draws if a seed is given
+    #[pyo3(signature = (state, seed=None))]
+    fn reset(
+        &mut self,
+        state: rust::policies::State,
+        seed: Option<u64>,
+    ) -> Result<rust::policies::State, rust::environment::EnvironmentError> {
+        self.env.reset(state, seed)
+    }
+
+    // info holds the cost split into transhipment_cost, store_cost and warehouse_cost, and the demand, dfw, online,
+    // emergency and lost_sales of the period
+    fn step(
+        &mut self,
+        py: Python<'_>,
+        action: rust::policies::Action,
+    ) -> Result<(rust::policies::State, f64, PyObject), rust::environment::EnvironmentError> {
+        let step = self.env.step(action)?;
+        let info = [
+            ("transhipment_cost", step.transhipment_cost.into_py(py)),
+            ("store_cost", step.store_cost.into_py(py)),
+            ("warehouse_cost", step.warehouse_cost.into_py(py)),
+            ("demand", step.demand.into_py(py)),
+            ("dfw", step.dfw.into_py(py)),
+            ("online", step.online.into_py(py)),
+            ("emergency", step.emergency.into_py(py)),
+            ("lost_sales", step.lost_sales.into_py(py)),
+        ]
+        .into_py_dict_bound(py);
+        Ok((step.next_state, step.cost, info.into_py(py)))
+    }
+
+    // Feasible actions in the current state, the same as the solvers consider
+    fn action_space(
+        &self,
+    ) -> Result<Vec<rust::policies::Action>, rust::environment::EnvironmentError> {
+        self.env.action_space()
+    }
+
+    #[getter]
+    fn state(&self) -> Option<rust::policies::State> {
+        self.env.state()
+    }
+
+    #[getter]
+    fn gamma(&self) -> f64 {
+        self.env.policy_constructor.gamma
+    }
+}
+
 /// A Python module implemented in Rust. The name of this function must match
 /// the `lib.name` setting in the `Cargo.toml`, else Python will not be able to
 /// import the module.
@@ -649,9 +1218,77 @@ fn _core(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(policy_evaluation_par_opt, m)?)?;
     m.add_function(wrap_pyfunction!(warehouse_store_expectations_py, m)?)?;
     m.add_function(wrap_pyfunction!(action_space_visualiser, m)?)?;
+    m.add_function(wrap_pyfunction!(q_function, m)?)?;
+    m.add_function(wrap_pyfunction!(truncation_diagnostics, m)?)?;
+    m.add_function(wrap_pyfunction!(auto_bounds, m)?)?;
+    m.add_function(wrap_pyfunction!(stationary_metrics, m)?)?;
+    m.add_class::<PyEnvironment>()?;
     //m.add_function(wrap_pyfunction!(pre_calculate_store_costs, m)?)?;
     //m.add_function(wrap_pyfunction!(pre_calculate_warehouse_costs, m)?)?;
     //m.add_function(wrap_pyfunction!(expectation_warehouse, m)?)?;
     //m.add_function(wrap_pyfunction!(expectation_store, m)?)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    // Runs optimal_policy_par on a model that takes far longer than the test allows and prints how it ended.
+    // Only run by ctrl_c_stops_the_parallel_solver, in a process of its own so this thread is Python's main thread
+    #[test]
+    #[ignore]
+    fn solve_until_interrupted() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            // An embedded interpreter leaves SIGINT alone, so install the handler python itself would
+            py.run_bound(
+                "import signal; signal.signal(signal.SIGINT, signal.default_int_handler)",
+                None,
+                None,
+            )
+            .unwrap();
+            let solver = wrap_pyfunction_bound!(optimal_policy_par, py).unwrap();
+            let kwargs = [("max_wh", 14), ("max_sa", 8), ("max_sb", 8)].into_py_dict_bound(py);
+            println!("solving");
+            match solver.call((6, 2.0, 1.5, 1.0, 0.5, 9.0, 0.5, 0.1), Some(&kwargs)) {
+                Err(err) if err.is_instance_of::<pyo3::exceptions::PyKeyboardInterrupt>(py) => {
+                    println!("interrupted")
+                }
+                Err(err) => println!("failed with {}", err),
+                Ok(_) => println!("finished"),
+            }
+        });
+    }
+
+    #[test]
+    fn ctrl_c_stops_the_parallel_solver() {
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::solve_until_interrupted", "--ignored", "--nocapture", "--test-threads=1"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        // libtest prints the test name without a newline, so "solving" ends the first line it writes
+        while !lines.next().expect("solver exited early").unwrap().ends_with("solving") {}
+        // Let the solve get under way before sending Ctrl-C
+        std::thread::sleep(Duration::from_secs(1));
+        Command::new("kill")
+            .args(["-INT", &child.id().to_string()])
+            .status()
+            .unwrap();
+        let sent = Instant::now();
+        while child.try_wait().unwrap().is_none() {
+            if sent.elapsed() > Duration::from_secs(60) {
+                child.kill().unwrap();
+                panic!("optimal_policy_par ignored Ctrl-C");
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let output: Vec<String> = lines.map(|line| line.unwrap()).collect();
+        assert!(output.iter().any(|line| line.contains("interrupted")), "{:?}", output);
+    }
+}
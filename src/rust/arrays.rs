@@ -0,0 +1,349 @@
+// Dense ndarray versions of the value functions and policy tables.
+// Much cheaper to hand back to Python than dicts of tuples for large state spaces.
+// Period t is stored in row t-1 of any array with a leading period axis.
+use crate::rust;
+use numpy::ndarray::{Array3, Array4, Array5, ArrayView5};
+use numpy::{IntoPyArray, PyReadonlyArray3, PyReadonlyArray4, PyReadonlyArray5};
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use std::collections::HashMap;
+
+// Policy keyed by (t, wh, sa, sb) with actions (wh_order, sa_order, sb_order, transhipments A->B, transhipments B->A)
+pub type PolicyTable = HashMap<(usize, usize, usize, usize), (usize, usize, usize, usize, usize)>;
+
+// DFW threshold keyed by (t, wh, sa, sb), as chosen by optimal_policy_par with optimise_dfw_threshold
+pub type DfwThresholdTable = HashMap<(usize, usize, usize, usize), usize>;
+
+// Checks a DFW threshold table covers every decision period and state
+pub fn validate_dfw_thresholds(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    periods: usize,
+    table: &DfwThresholdTable,
+) -> PyResult<()> {
+    for t in 1..=periods {
+        for state in policy.construct_state_space_iterator() {
+            if !table.contains_key(&(t, state.0, state.1, state.2)) {
+                return Err(PyValueError::new_err(format!(
+                    "DFW thresholds are missing period {} state {:?}",
+                    t, state
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Value function with shape (max_wh, max_sa, max_sb)
+pub fn value_function_array(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    v: impl Iterator<Item = ((usize, usize, usize), f64)>,
+) -> Array3<f64> {
+    let mut v_array = Array3::zeros((policy.max_wh, policy.max_sa, policy.max_sb));
+    for (state, value) in v {
+        v_array[[state.0, state.1, state.2]] = value;
+    }
+    v_array
+}
+
+// Value function for every period with shape (periods + 1, max_wh, max_sa, max_sb), the last row is the terminal period
+pub fn all_periods_value_array(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    periods: usize,
+    v_all: &HashMap<(usize, usize, usize, usize), f64>,
+) -> Array4<f64> {
+    let mut v_array = Array4::zeros((periods + 1, policy.max_wh, policy.max_sa, policy.max_sb));
+    for (key, value) in v_all {
+        v_array[[key.0 - 1, key.1, key.2, key.3]] = *value;
+    }
+    v_array
+}
+
+// Value function handed back to Python, covering every period when all_periods is set
+pub fn value_function_pyarray<'py>(
+    py: Python<'py>,
+    policy: &rust::policy_contructor::OptimalPolicy,
+    periods: usize,
+    all_periods: bool,
+    v: impl Iterator<Item = ((usize, usize, usize), f64)>,
+    v_all: &HashMap<(usize, usize, usize, usize), f64>,
+) -> Bound<'py, PyAny> {
+    if all_periods {
+        all_periods_value_array(policy, periods, v_all)
+            .into_pyarray_bound(py)
+            .into_any()
+    } else {
+        value_function_array(policy, v).into_pyarray_bound(py).into_any()
+    }
+}
+
+// Policy with shape (periods, max_wh, max_sa, max_sb, 5), one row per decision period
+pub fn policy_array(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    periods: usize,
+    pol: impl Iterator<Item = ((usize, usize, usize, usize), (usize, usize, usize, usize, usize))>,
+) -> Array5<usize> {
+    let mut pol_array = Array5::zeros((
+        periods,
+        policy.max_wh,
+        policy.max_sa,
+        policy.max_sb,
+        5,
+    ));
+    for (key, action) in pol {
+        let action = [action.0, action.1, action.2, action.3, action.4];
+        for (i, a) in action.iter().enumerate() {
+            pol_array[[key.0 - 1, key.1, key.2, key.3, i]] = *a;
+        }
+    }
+    pol_array
+}
+
+// Policy passed in from Python, either as a dict, as an array laid out like policy_array
+// or as a callable f(t, wh, sa, sb) returning the action
+pub enum PolicyInput<'py> {
+    Dict(PolicyTable),
+    Unsigned(PyReadonlyArray5<'py, usize>),
+    Signed(PyReadonlyArray5<'py, i64>),
+    Callable(Bound<'py, PyAny>),
+}
+
+impl<'py> FromPyObject<'py> for PolicyInput<'py> {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if ob.is_callable() {
+            return Ok(PolicyInput::Callable(ob.clone()));
+        }
+        if let Ok(table) = ob.extract() {
+            return Ok(PolicyInput::Dict(table));
+        }
+        if let Ok(actions) = ob.extract() {
+            return Ok(PolicyInput::Unsigned(actions));
+        }
+        if let Ok(actions) = ob.extract() {
+            return Ok(PolicyInput::Signed(actions));
+        }
+        Err(PyTypeError::new_err(
+            "Policy must be a dict, an integer array or a callable f(t, wh, sa, sb)",
+        ))
+    }
+}
+
+impl PolicyInput<'_> {
+    // Table of the policy, checked to have a feasible action for every decision period and state
+    pub fn into_table(
+        self,
+        policy: &rust::policy_contructor::OptimalPolicy,
+        periods: usize,
+    ) -> PyResult<PolicyTable> {
+        let table = match self {
+            PolicyInput::Dict(table) => Ok(table),
+            PolicyInput::Unsigned(actions) => policy_table_from_array(
+                policy,
+                periods,
+                actions.as_array(),
+                Some,
+            ),
+            PolicyInput::Signed(actions) => policy_table_from_array(
+                policy,
+                periods,
+                actions.as_array(),
+                |a| usize::try_from(a).ok(),
+            ),
+            PolicyInput::Callable(callback) => {
+                rust::policies::callable::callable_policy_table(policy, periods, &callback)
+            }
+        }?;
+        validate_policy_table(policy, periods, &table)?;
+        Ok(table)
+    }
+}
+
+// Every decision period and state needs an action in the action space, evaluating anything else would index outside the state space
+fn validate_policy_table(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    periods: usize,
+    table: &PolicyTable,
+) -> PyResult<()> {
+    for t in 1..=periods {
+        for state in policy.construct_state_space_iterator() {
+            match table.get(&(t, state.0, state.1, state.2)) {
+                None => {
+                    return Err(PyValueError::new_err(format!(
+                        "Policy is missing an action in period {} state {:?}",
+                        t, state
+                    )))
+                }
+                Some(action) if !policy.is_feasible_action(state, *action) => {
+                    return Err(PyValueError::new_err(format!(
+                        "Policy action {:?} in period {} is not feasible in state {:?}",
+                        action, t, state
+                    )))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn policy_table_from_array<T: Copy>(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    periods: usize,
+    actions: ArrayView5<T>,
+    to_usize: impl Fn(T) -> Option<usize>,
+) -> PyResult<PolicyTable> {
+    let expected_shape = [
+        periods,
+        policy.max_wh,
+        policy.max_sa,
+        policy.max_sb,
+        5,
+    ];
+    if actions.shape() != expected_shape {
+        return Err(PyValueError::new_err(format!(
+            "Policy array has shape {:?}, expected {:?}",
+            actions.shape(),
+            expected_shape
+        )));
+    }
+    let mut table = HashMap::new();
+    for t in 1..=periods {
+        for state in policy.construct_state_space_iterator() {
+            let mut action = [0; 5];
+            for (i, a) in action.iter_mut().enumerate() {
+                *a = to_usize(actions[[t - 1, state.0, state.1, state.2, i]]).ok_or_else(|| {
+                    PyValueError::new_err(format!(
+                        "Policy array has a negative action in period {} state {:?}",
+                        t, state
+                    ))
+                })?;
+            }
+            table.insert(
+                (t, state.0, state.1, state.2),
+                (action[0], action[1], action[2], action[3], action[4]),
+            );
+        }
+    }
+    Ok(table)
+}
+
+// Value function passed in from Python, either for a single period keyed by (wh, sa, sb) or shaped (max_wh, max_sa, max_sb),
+// or for every period as returned with all_periods keyed by (t, wh, sa, sb) or shaped (periods + 1, max_wh, max_sa, max_sb)
+pub enum ValueFunctionInput<'py> {
+    Dict(HashMap<(usize, usize, usize), f64>),
+    AllPeriodsDict(HashMap<(usize, usize, usize, usize), f64>),
+    Array(PyReadonlyArray3<'py, f64>),
+    AllPeriodsArray(PyReadonlyArray4<'py, f64>),
+}
+
+impl<'py> FromPyObject<'py> for ValueFunctionInput<'py> {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if let Ok(v) = ob.extract() {
+            return Ok(ValueFunctionInput::Dict(v));
+        }
+        if let Ok(v) = ob.extract() {
+            return Ok(ValueFunctionInput::AllPeriodsDict(v));
+        }
+        if let Ok(v) = ob.extract() {
+            return Ok(ValueFunctionInput::Array(v));
+        }
+        if let Ok(v) = ob.extract() {
+            return Ok(ValueFunctionInput::AllPeriodsArray(v));
+        }
+        Err(PyTypeError::new_err(
+            "Value function must be a dict or a float array",
+        ))
+    }
+}
+
+impl ValueFunctionInput<'_> {
+    // Value function of period t, which has to cover every state
+    pub fn into_period(
+        self,
+        policy: &rust::policy_contructor::OptimalPolicy,
+        t: usize,
+    ) -> PyResult<HashMap<(usize, usize, usize), f64>> {
+        let mut v = HashMap::new();
+        for state in policy.construct_state_space_iterator() {
+            let value = match &self {
+                ValueFunctionInput::Dict(values) => values.get(&state).copied(),
+                ValueFunctionInput::AllPeriodsDict(values) => {
+                    values.get(&(t, state.0, state.1, state.2)).copied()
+                }
+                ValueFunctionInput::Array(values) => {
+                    values.as_array().get([state.0, state.1, state.2]).copied()
+                }
+                ValueFunctionInput::AllPeriodsArray(values) => t.checked_sub(1).and_then(|row| {
+                    values.as_array().get([row, state.0, state.1, state.2]).copied()
+                }),
+            };
+            match value {
+                Some(value) => v.insert(state, value),
+                None => {
+                    return Err(PyValueError::new_err(format!(
+                        "Value function for period {} is missing state {:?}",
+                        t, state
+                    )))
+                }
+            };
+        }
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::OptimalPolicy;
+
+    #[test]
+    fn policy_array_round_trips_through_the_table() {
+        pyo3::prepare_freethreaded_python();
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let periods = 2;
+        let v = rust::value_function::terminal_cost(&policy_constructor, Some((1.0, 1.0, 1.0)), None).unwrap();
+        let mut v_all = HashMap::new();
+        rust::value_function::record_period_values(
+            &mut v_all,
+            periods + 1,
+            v.iter().map(|entry| (*entry.key(), *entry.value())),
+        );
+        let terminal: HashMap<(usize, usize, usize), f64> = v.iter().map(|entry| (*entry.key(), *entry.value())).collect();
+        let progress = rust::progress::ProgressTracker::new(None, 0, 0);
+        let optimal_pol =
+            rust::optimal::solve_optimal_policy(&policy_constructor, periods, &v, Some(&mut v_all), None, &progress)
+                .unwrap();
+        let table: PolicyTable = optimal_pol.into_iter().collect();
+
+        let pol_array = policy_array(&policy_constructor, periods, table.clone().into_iter());
+        assert_eq!(pol_array.shape(), [periods, 6, 4, 4, 5]);
+        for (key, action) in &table {
+            assert_eq!(pol_array[[key.0 - 1, key.1, key.2, key.3, 0]], action.0);
+            assert_eq!(pol_array[[key.0 - 1, key.1, key.2, key.3, 4]], action.4);
+        }
+        let round_trip = policy_table_from_array(&policy_constructor, periods, pol_array.view(), Some).unwrap();
+        assert_eq!(round_trip, table);
+        validate_policy_table(&policy_constructor, periods, &round_trip).unwrap();
+        // The array of a longer horizon doesn't pass as this one
+        assert!(policy_table_from_array(&policy_constructor, periods - 1, pol_array.view(), Some).is_err());
+
+        let v_array = all_periods_value_array(&policy_constructor, periods, &v_all);
+        assert_eq!(v_array.shape(), [periods + 1, 6, 4, 4]);
+        for (key, value) in &v_all {
+            assert_eq!(v_array[[key.0 - 1, key.1, key.2, key.3]], *value);
+        }
+        // The last row is the terminal cost
+        for (state, value) in &terminal {
+            assert_eq!(v_array[[periods, state.0, state.1, state.2]], *value);
+        }
+    }
+
+    #[test]
+    fn negative_actions_are_rejected() {
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let mut actions = Array5::<i64>::zeros((1, 6, 4, 4, 5));
+        assert!(policy_table_from_array(&policy_constructor, 1, actions.view(), |a| usize::try_from(a).ok()).is_ok());
+        actions[[0, 2, 1, 1, 3]] = -1;
+        assert!(policy_table_from_array(&policy_constructor, 1, actions.view(), |a| usize::try_from(a).ok()).is_err());
+    }
+}
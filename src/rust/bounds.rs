@@ -0,0 +1,203 @@
+// Automatic choice of the state-space bounds (max_wh, max_sa, max_sb).
+// Starts from newsvendor critical fractiles of demand over the lead time plus review period (two periods with LT=1),
+// then re-solves with larger bounds wherever the truncation diagnostics ask for more room until they stop asking.
+use crate::rust;
+use crate::rust::policies::{Action, State};
+use dashmap::DashMap;
+use pyo3::prelude::*;
+
+// Periods of demand an order-up-to level has to cover, the lead time of one period plus the review period
+const COVERAGE_PERIODS: usize = 2;
+
+pub struct AutoBounds {
+    pub initial_bounds: (usize, usize, usize),
+    pub bounds: (usize, usize, usize),
+    pub iterations: usize,
+    // Whether the last diagnostics were within tolerance, false if max_iterations ran out first.
+    // Only the state-space bounds count, demand beyond D_MAX is reported in demand_truncated
+    pub converged: bool,
+    // Whether either store's demand pmf drops more than tolerance beyond D_MAX, which growing the bounds can't fix
+    pub demand_truncated: bool,
+    pub report: rust::diagnostics::TruncationReport,
+    pub optimal_pol: DashMap<(usize, usize, usize, usize), Action>,
+    pub v: DashMap<State, f64>,
+}
+
+// Bounds from the critical fractiles, each store covers its own demand at its own c_u_s / (c_u_s + h_s)
+// and the warehouse covers both stores' demand at c_u_s / (c_u_s + h_w) with the larger c_u_s, plus safety_margin units of room.
+// A state space of size n holds levels 0..n-1, hence the extra unit.
+pub fn fractile_bounds(
+    policy_constructor: &rust::policy_contructor::OptimalPolicy,
+    safety_margin: usize,
+) -> (usize, usize, usize) {
+    let store_fractile = |c_u_s: f64, h_s: f64| c_u_s / (c_u_s + h_s);
+    // The warehouse backs up both stores, so it takes the larger of their lost sales costs
+    let c_u_s = f64::max(policy_constructor.c_u_s.0, policy_constructor.c_u_s.1);
+    let warehouse_fractile = c_u_s / (c_u_s + policy_constructor.h_w);
+    let da_cover = repeat_convolve(&policy_constructor.da_pmf, COVERAGE_PERIODS);
+    let db_cover = repeat_convolve(&policy_constructor.db_pmf, COVERAGE_PERIODS);
+    let total_cover = convolve(&da_cover, &db_cover);
+    (
+        quantile(&total_cover, warehouse_fractile) + safety_margin + 1,
+        quantile(
+            &da_cover,
+            store_fractile(policy_constructor.c_u_s.0, policy_constructor.h_s.0),
+        ) + safety_margin
+            + 1,
+        quantile(
+            &db_cover,
+            store_fractile(policy_constructor.c_u_s.1, policy_constructor.h_s.1),
+        ) + safety_margin
+            + 1,
+    )
+}
+
+// Solves with the fractile bounds and grows each bound the diagnostics recommend enlarging by growth_step
+// until no measure exceeds tolerance. Solve time grows quickly with the bounds so small steps are used
+// rather than jumping straight to the recommended bounds.
+pub fn auto_bounds(
+    policy_constructor: &mut rust::policy_contructor::OptimalPolicy,
+    periods: usize,
+    safety_margin: usize,
+    growth_step: usize,
+    tolerance: f64,
+    max_iterations: usize,
+    initial_state: State,
+) -> PyResult<AutoBounds> {
+    let initial_bounds = fractile_bounds(policy_constructor, safety_margin);
+    let mut bounds = initial_bounds;
+    let mut iterations = 0;
+    loop {
+        policy_constructor.max_wh = bounds.0;
+        policy_constructor.max_sa = bounds.1;
+        policy_constructor.max_sb = bounds.2;
+        iterations += 1;
+
+        let v = rust::value_function::terminal_cost(policy_constructor, None, None)?;
+        let progress = rust::progress::ProgressTracker::new(None, 0, 0);
+        let optimal_pol =
+            rust::optimal::solve_optimal_policy(policy_constructor, periods, &v, None, None, &progress)?;
+        let optimal_pol_hm = optimal_pol
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect::<rust::arrays::PolicyTable>();
+        // The diagnostics start from the initial state, which has to stay inside the state space
+        let start = (
+            initial_state.0.min(bounds.0 - 1),
+            initial_state.1.min(bounds.1 - 1),
+            initial_state.2.min(bounds.2 - 1),
+        );
+        let report = rust::diagnostics::truncation_report(
+            policy_constructor,
+            &rust::policies::TablePolicy {
+                actions: &optimal_pol_hm,
+                dfw_thresholds: None,
+            },
+            periods,
+            start,
+            tolerance,
+        );
+
+        match report.recommended_bounds {
+            Some(recommended) if iterations < max_iterations => {
+                let grow = |bound: usize, recommended: usize| {
+                    if recommended > bound {
+                        bound + growth_step.max(1)
+                    } else {
+                        bound
+                    }
+                };
+                bounds = (
+                    grow(bounds.0, recommended.0),
+                    grow(bounds.1, recommended.1),
+                    grow(bounds.2, recommended.2),
+                );
+            }
+            recommended => {
+                return Ok(AutoBounds {
+                    initial_bounds,
+                    bounds,
+                    iterations,
+                    converged: recommended.is_none(),
+                    demand_truncated: report.pmf_tail_mass.0 > tolerance
+                        || report.pmf_tail_mass.1 > tolerance,
+                    report,
+                    optimal_pol,
+                    v,
+                })
+            }
+        }
+    }
+}
+
+// Smallest level whose cumulative probability reaches the fractile
+pub fn quantile(pmf: &[f64], fractile: f64) -> usize {
+    let mut cdf = 0.0;
+    for (level, prob) in pmf.iter().enumerate() {
+        cdf += prob;
+        if cdf >= fractile {
+            return level;
+        }
+    }
+    pmf.len() - 1
+}
+
+pub fn convolve(a: &[f64], b: &[f64]) -> Vec<f64> {
+    let mut out = vec![0.0; a.len() + b.len() - 1];
+    for (i, pa) in a.iter().enumerate() {
+        for (j, pb) in b.iter().enumerate() {
+            out[i + j] += pa * pb;
+        }
+    }
+    out
+}
+
+pub fn repeat_convolve(pmf: &[f64], n: usize) -> Vec<f64> {
+    let mut out = pmf.to_vec();
+    for _ in 1..n {
+        out = convolve(&out, pmf);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::OptimalPolicy;
+
+    fn assert_pmf_eq(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-12, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn pmf_helpers_match_hand_computed_values() {
+        assert_pmf_eq(&convolve(&[0.5, 0.5], &[0.25, 0.75]), &[0.125, 0.5, 0.375]);
+        assert_pmf_eq(&repeat_convolve(&[0.5, 0.5], 3), &[0.125, 0.375, 0.375, 0.125]);
+        assert_pmf_eq(&repeat_convolve(&[0.2, 0.8], 1), &[0.2, 0.8]);
+
+        let pmf = [0.2, 0.3, 0.5];
+        assert_eq!(quantile(&pmf, 0.2), 0);
+        assert_eq!(quantile(&pmf, 0.5), 1);
+        assert_eq!(quantile(&pmf, 0.51), 2);
+        // A truncated pmf never reaches fractiles above its mass, the top level is the best it can do
+        assert_eq!(quantile(&[0.2, 0.3], 0.9), 1);
+    }
+
+    #[test]
+    fn fractile_bounds_cover_two_periods_of_demand() {
+        let mut policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        // Store A sells 0 or 1 unit with equal probability, store B never sells
+        policy_constructor.da_pmf = [0.0; crate::D_MAX];
+        policy_constructor.da_pmf[..2].copy_from_slice(&[0.5, 0.5]);
+        policy_constructor.db_pmf = [0.0; crate::D_MAX];
+        policy_constructor.db_pmf[0] = 1.0;
+        // Two periods of store A demand are [0.25, 0.5, 0.25]. Store A's fractile 9 / (9 + 1) and the warehouse's
+        // 9 / (9 + 0.5) both need 2 units, store B none, then one unit of margin and one for level 0
+        assert_eq!(fractile_bounds(&policy_constructor, 1), (4, 4, 2));
+        assert_eq!(fractile_bounds(&policy_constructor, 0), (3, 3, 1));
+    }
+}
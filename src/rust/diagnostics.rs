@@ -0,0 +1,247 @@
+// Diagnostics for the truncation of the state space at (max_wh, max_sa, max_sb) and of demand at D_MAX.
+// The state distribution is pushed forward through a policy from an initial state to see how often it presses against the bounds.
+use crate::rust;
+use crate::rust::policies::{Action, Policy, State};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::collections::HashMap;
+
+pub struct TruncationReport {
+    // Probability that (warehouse, store A, store B) sits at its largest level, for each period 1..=periods + 1
+    pub boundary_mass_by_period: Vec<(f64, f64, f64)>,
+    // Average of boundary_mass_by_period
+    pub boundary_mass: (f64, f64, f64),
+    // Probability per decision period that each part of the action is positive and as large as the bounds allow,
+    // laid out like the action (wh_order, sa_order, sb_order, transhipments A->B, transhipments B->A)
+    pub capped_actions: (f64, f64, f64, f64, f64),
+    // Demand probability beyond D_MAX that is dropped from the pmf of store A and store B
+    pub pmf_tail_mass: (f64, f64),
+    // Larger (max_wh, max_sa, max_sb) when any state-space measure exceeds the threshold, the pmf tail only adds a warning
+    // since D_MAX is fixed whatever the bounds
+    pub recommended_bounds: Option<(usize, usize, usize)>,
+    pub warnings: Vec<String>,
+}
+
+impl TruncationReport {
+    pub fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let out = PyDict::new_bound(py);
+        out.set_item("boundary_mass", self.boundary_mass)?;
+        out.set_item("boundary_mass_by_period", &self.boundary_mass_by_period)?;
+        out.set_item("capped_actions", self.capped_actions)?;
+        out.set_item("pmf_tail_mass", self.pmf_tail_mass)?;
+        out.set_item("recommended_bounds", self.recommended_bounds)?;
+        out.set_item("warnings", &self.warnings)?;
+        Ok(out)
+    }
+}
+
+pub fn truncation_report(
+    policy_constructor: &rust::policy_contructor::OptimalPolicy,
+    policy: &dyn Policy,
+    periods: usize,
+    initial_state: State,
+    threshold: f64,
+) -> TruncationReport {
+    let top = (
+        policy_constructor.max_wh - 1,
+        policy_constructor.max_sa - 1,
+        policy_constructor.max_sb - 1,
+    );
+    let mut boundary_mass_by_period = Vec::new();
+    let mut capped = [0.0; 5];
+    let mut dist: HashMap<State, f64> = HashMap::from([(initial_state, 1.0)]);
+    for t in 1..=periods + 1 {
+        // Demand beyond D_MAX is dropped so the mass leaks a little each period, report shares of what is left
+        let total: f64 = dist.values().sum();
+        let mut at_boundary = (0.0, 0.0, 0.0);
+        for (state, prob) in &dist {
+            let prob = prob / total;
+            if state.0 == top.0 {
+                at_boundary.0 += prob;
+            }
+            if state.1 == top.1 {
+                at_boundary.1 += prob;
+            }
+            if state.2 == top.2 {
+                at_boundary.2 += prob;
+            }
+        }
+        boundary_mass_by_period.push(at_boundary);
+        // Period `periods + 1` is terminal
+        if t > periods {
+            break;
+        }
+
+        let mut next_dist: HashMap<State, f64> = HashMap::new();
+        for (state, prob) in &dist {
+            let prob = prob / total;
+            let dfw_threshold = policy
+                .dfw_threshold(t, *state)
+                .unwrap_or(policy_constructor.dfw_threshold);
+            for (action, action_prob) in policy.action_distribution(t, *state) {
+                let prob = prob * action_prob;
+                for (i, is_capped) in capped_parts(policy_constructor, *state, action)
+                    .iter()
+                    .enumerate()
+                {
+                    if *is_capped {
+                        capped[i] += prob;
+                    }
+                }
+                let (wh_order, st_a_order, st_b_order, t_a_to_b, t_b_to_a) = action;
+                let post_state = (
+                    state.0 - st_a_order - st_b_order,
+                    state.1 - t_a_to_b + t_b_to_a,
+                    state.2 - t_b_to_a + t_a_to_b,
+                );
+                rust::value_function::for_each_transition_rationed(
+                    policy_constructor,
+                    post_state,
+                    (wh_order, st_a_order, st_b_order),
+                    dfw_threshold,
+                    |next_state, transition_prob| {
+                        *next_dist.entry(next_state).or_insert(0.0) += prob * transition_prob;
+                    },
+                );
+            }
+        }
+        dist = next_dist;
+    }
+
+    let n_periods = boundary_mass_by_period.len() as f64;
+    let boundary_mass = boundary_mass_by_period
+        .iter()
+        .fold((0.0, 0.0, 0.0), |acc, mass| {
+            (acc.0 + mass.0, acc.1 + mass.1, acc.2 + mass.2)
+        });
+    let boundary_mass = (
+        boundary_mass.0 / n_periods,
+        boundary_mass.1 / n_periods,
+        boundary_mass.2 / n_periods,
+    );
+    let decision_periods = periods.max(1) as f64;
+    let capped_actions = (
+        capped[0] / decision_periods,
+        capped[1] / decision_periods,
+        capped[2] / decision_periods,
+        capped[3] / decision_periods,
+        capped[4] / decision_periods,
+    );
+    let pmf_tail_mass = (
+        (1.0 - policy_constructor.da_pmf.iter().sum::<f64>()).max(0.0),
+        (1.0 - policy_constructor.db_pmf.iter().sum::<f64>()).max(0.0),
+    );
+
+    // Grow any bound that is pressed against, store transhipments are capped by the receiving store
+    let mut warnings = Vec::new();
+    let mut grow = (false, false, false);
+    let mut check = |value: f64, what: &str, grow_bound: &mut bool| {
+        if value > threshold {
+            warnings.push(format!("{} with probability {:.4}", what, value));
+            *grow_bound = true;
+        }
+    };
+    check(boundary_mass.0, "Warehouse at max_wh - 1", &mut grow.0);
+    check(boundary_mass.1, "Store A at max_sa - 1", &mut grow.1);
+    check(boundary_mass.2, "Store B at max_sb - 1", &mut grow.2);
+    check(capped_actions.0, "Warehouse order capped by max_wh", &mut grow.0);
+    check(capped_actions.1, "Store A order capped by max_sa", &mut grow.1);
+    check(capped_actions.2, "Store B order capped by max_sb", &mut grow.2);
+    check(capped_actions.3, "Transhipment A->B capped by max_sb", &mut grow.2);
+    check(capped_actions.4, "Transhipment B->A capped by max_sa", &mut grow.1);
+    for (tail, store) in [(pmf_tail_mass.0, "A"), (pmf_tail_mass.1, "B")] {
+        if tail > threshold {
+            warnings.push(format!(
+                "Store {} demand pmf drops {:.4} probability beyond D_MAX = {}, increase D_MAX",
+                store,
+                tail,
+                crate::D_MAX
+            ));
+        }
+    }
+    let enlarge = |bound: usize, grow_bound: bool| {
+        if grow_bound {
+            bound + bound.div_ceil(2)
+        } else {
+            bound
+        }
+    };
+    let recommended_bounds = if grow.0 || grow.1 || grow.2 {
+        Some((
+            enlarge(policy_constructor.max_wh, grow.0),
+            enlarge(policy_constructor.max_sa, grow.1),
+            enlarge(policy_constructor.max_sb, grow.2),
+        ))
+    } else {
+        None
+    };
+
+    TruncationReport {
+        boundary_mass_by_period,
+        boundary_mass,
+        capped_actions,
+        pmf_tail_mass,
+        recommended_bounds,
+        warnings,
+    }
+}
+
+// Which parts of the action are positive and at the most the bounds allow
+fn capped_parts(
+    policy_constructor: &rust::policy_contructor::OptimalPolicy,
+    state: State,
+    action: Action,
+) -> [bool; 5] {
+    let (wh_order, st_a_order, st_b_order, t_a_to_b, t_b_to_a) = action;
+    let post_state = (
+        state.0 - st_a_order - st_b_order,
+        state.1 - t_a_to_b + t_b_to_a,
+        state.2 - t_b_to_a + t_a_to_b,
+    );
+    [
+        wh_order > 0 && post_state.0 + wh_order == policy_constructor.max_wh - 1,
+        st_a_order > 0 && post_state.1 + st_a_order == policy_constructor.max_sa - 1,
+        st_b_order > 0 && post_state.2 + st_b_order == policy_constructor.max_sb - 1,
+        t_a_to_b > 0 && post_state.2 == policy_constructor.max_sb - 1,
+        t_b_to_a > 0 && post_state.1 == policy_constructor.max_sa - 1,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policies::base_stock::{AllocationRule, RegularBaseStock};
+    use crate::rust::policies::{Composite, NoTranshipment};
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::OptimalPolicy;
+
+    fn base_stock(levels: (usize, usize, usize)) -> Composite<'static> {
+        Composite {
+            ordering: Box::new(RegularBaseStock::new(levels, None, AllocationRule::LargestRequest)),
+            transhipment: Box::new(NoTranshipment),
+        }
+    }
+
+    #[test]
+    fn bounds_grow_when_the_initial_state_is_on_the_boundary() {
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let report = truncation_report(&policy_constructor, &base_stock((5, 3, 3)), 1, (5, 3, 3), 0.01);
+        assert_eq!(report.boundary_mass_by_period.len(), 2);
+        assert_eq!(report.boundary_mass_by_period[0], (1.0, 1.0, 1.0));
+        // Every bound grows by half, rounded up
+        assert_eq!(report.recommended_bounds, Some((9, 6, 6)));
+        assert!(!report.warnings.is_empty());
+    }
+
+    #[test]
+    fn nothing_is_recommended_away_from_the_boundary() {
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        // Base-stock levels below the top of every bound, so no state or order reaches it
+        let report = truncation_report(&policy_constructor, &base_stock((2, 1, 1)), 3, (0, 0, 0), 0.01);
+        assert_eq!(report.boundary_mass_by_period.len(), 4);
+        assert_eq!(report.boundary_mass, (0.0, 0.0, 0.0));
+        assert_eq!(report.capped_actions, (0.0, 0.0, 0.0, 0.0, 0.0));
+        assert_eq!(report.recommended_bounds, None);
+        assert!(report.warnings.is_empty());
+    }
+}
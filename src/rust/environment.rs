@@ -0,0 +1,140 @@
+// Simulation of the model one period at a time, for agents trained against the same dynamics the DP solves.
+// Each period is drawn by OptimalPolicy::sample_demand_outcome, so walkers, DFW, online orders and emergency transhipments
+// follow the same rules, and its cost is the outcome whose expectation immediate_cost takes.
+use crate::rust;
+use crate::rust::policies::{Action, State};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+// What happened in one period
+pub struct Step {
+    pub next_state: State,
+    pub cost: f64,
+    pub transhipment_cost: f64,
+    pub store_cost: f64,
+    pub warehouse_cost: f64,
+    // Demand at (store A, store B) once walkers and emergency transhipments have moved it to the store serving it
+    pub demand: (usize, usize),
+    pub dfw: (usize, usize),
+    // Online orders (filled, lost)
+    pub online: (usize, usize),
+    // Units emergency transhipped (A->B, B->A)
+    pub emergency: (usize, usize),
+    // Customers lost at (store A, store B)
+    pub lost_sales: (usize, usize),
+}
+
+// Misuse of the environment, raised in Python as ValueError
+pub enum EnvironmentError {
+    NotReset,
+    StateOutside(State),
+    InfeasibleAction(State, Action),
+}
+
+impl From<EnvironmentError> for PyErr {
+    fn from(err: EnvironmentError) -> PyErr {
+        PyValueError::new_err(match err {
+            EnvironmentError::NotReset => "Call reset first".to_string(),
+            EnvironmentError::StateOutside(state) => {
+                format!("State {:?} is outside the state space", state)
+            }
+            EnvironmentError::InfeasibleAction(state, action) => format!(
+                "Action {:?} is not in the action space of state {:?}",
+                action, state
+            ),
+        })
+    }
+}
+
+pub struct Environment {
+    pub policy_constructor: rust::policy_contructor::OptimalPolicy,
+    // None until the first reset
+    state: Option<State>,
+    rng: StdRng,
+}
+
+impl Environment {
+    pub fn new(policy_constructor: rust::policy_contructor::OptimalPolicy, seed: u64) -> Self {
+        Environment {
+            policy_constructor,
+            state: None,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn state(&self) -> Option<State> {
+        self.state
+    }
+
+    // Starts from state, reseeding the draws if a seed is given
+    pub fn reset(&mut self, state: State, seed: Option<u64>) -> Result<State, EnvironmentError> {
+        let policy_constructor = &self.policy_constructor;
+        if state.0 >= policy_constructor.max_wh
+            || state.1 >= policy_constructor.max_sa
+            || state.2 >= policy_constructor.max_sb
+        {
+            return Err(EnvironmentError::StateOutside(state));
+        }
+        if let Some(seed) = seed {
+            self.rng = StdRng::seed_from_u64(seed);
+        }
+        self.state = Some(state);
+        Ok(state)
+    }
+
+    // Feasible actions in the current state, as generate_action_space
+    pub fn action_space(&self) -> Result<Vec<Action>, EnvironmentError> {
+        let state = self.current_state()?;
+        Ok(self.policy_constructor.generate_action_space(state))
+    }
+
+    // Takes action in the current state and draws the period's demand
+    pub fn step(&mut self, action: Action) -> Result<Step, EnvironmentError> {
+        let state = self.current_state()?;
+        let policy_constructor = &self.policy_constructor;
+        if !policy_constructor.is_feasible_action(state, action) {
+            return Err(EnvironmentError::InfeasibleAction(state, action));
+        }
+        let (wh_order, st_a_order, st_b_order, t_a_to_b, t_b_to_a) = action;
+        let post_state = (
+            state.0 - st_a_order - st_b_order,
+            state.1 - t_a_to_b + t_b_to_a,
+            state.2 - t_b_to_a + t_a_to_b,
+        );
+        let outcome = policy_constructor.sample_demand_outcome(post_state, &mut self.rng);
+        let (demand, dfw, online, emergency) = outcome;
+
+        let transhipment_cost = policy_constructor.transhipment_cost(t_a_to_b, t_b_to_a);
+        let store_cost = policy_constructor.store_outcome_cost(post_state, outcome);
+        let warehouse_cost = policy_constructor.warehouse_outcome_cost(post_state, outcome);
+        let next_state = rust::value_function::next_state(
+            post_state,
+            (wh_order, st_a_order, st_b_order),
+            demand,
+            dfw,
+            online,
+        );
+        self.state = Some(next_state);
+        Ok(Step {
+            next_state,
+            cost: transhipment_cost + store_cost + warehouse_cost,
+            transhipment_cost,
+            store_cost,
+            warehouse_cost,
+            demand,
+            dfw,
+            online,
+            emergency,
+            lost_sales: (
+                demand.0.saturating_sub(post_state.1) - dfw.0,
+                demand.1.saturating_sub(post_state.2) - dfw.1,
+            ),
+        })
+    }
+
+    fn current_state(&self) -> Result<State, EnvironmentError> {
+        self.state.ok_or(EnvironmentError::NotReset)
+    }
+}
@@ -0,0 +1,90 @@
+// Backward induction for a fixed policy, shared by every policy evaluation entry point
+use crate::rust;
+use crate::rust::policies::{Action, Policy, State};
+use dashmap::DashMap;
+use pyo3::prelude::*;
+use std::collections::HashMap;
+
+// Evaluates the policy over the decision periods 1..=periods.
+// v holds the terminal cost (period periods + 1) on entry and the first period's value function on exit.
+// When v_all is given every period's value function is recorded in it.
+// Returns the action taken in every period and state, keyed by (t, wh, sa, sb).
+// For randomised policies the value is the exact expectation over their actions and the most likely action is returned.
+// Policies that set their own DFW threshold are costed with it in place of the model's.
+pub fn evaluate_policy(
+    policy_constructor: &rust::policy_contructor::OptimalPolicy,
+    policy: &dyn Policy,
+    periods: usize,
+    v: &DashMap<State, f64>,
+    mut v_all: Option<&mut HashMap<(usize, usize, usize, usize), f64>>,
+    progress: &rust::progress::ProgressTracker,
+) -> PyResult<DashMap<(usize, usize, usize, usize), Action>> {
+    let store_expectation = policy_constructor.expectation_all_stores();
+    let warehouse_expectation = policy_constructor.expectation_all_warehouse();
+    let actions_taken: DashMap<(usize, usize, usize, usize), Action> = DashMap::new();
+
+    // Iterate through periods
+    for t in (1..=periods).rev() {
+        // Save previous iteration (v_t+1)
+        let v_plus_1_hm = v
+            .clone()
+            .into_iter()
+            .collect::<HashMap<(usize, usize, usize), f64>>();
+
+        // Iterate through all states
+        let state_space: Vec<(usize, usize, usize)> = policy_constructor
+            .construct_state_space_iterator()
+            .collect();
+        progress.solve_period(t, &state_space, |state| {
+            let state = (state.0, state.1, state.2);
+            let actions = policy.action_distribution(t, state);
+            let dfw_threshold = policy.dfw_threshold(t, state);
+
+            // Calculate the value function, in expectation over the actions of a randomised policy
+            let v_t_x: f64 = actions
+                .iter()
+                .map(|(action, prob)| {
+                    let value = match dfw_threshold {
+                        Some(dfw_threshold) => {
+                            let (im_cost, fut_cost) =
+                                rust::value_function::action_cost_breakdown_rationed(
+                                    policy_constructor,
+                                    state,
+                                    &v_plus_1_hm,
+                                    *action,
+                                    dfw_threshold,
+                                );
+                            im_cost + fut_cost
+                        }
+                        None => rust::value_function::value_function_pol_eval(
+                            policy_constructor,
+                            state,
+                            &v_plus_1_hm,
+                            *action,
+                            &store_expectation,
+                            &warehouse_expectation,
+                        ),
+                    };
+                    prob * value
+                })
+                .sum();
+            v.insert(state, v_t_x);
+            // Store the action taken (the most likely one for a randomised policy)
+            let mut action = actions[0];
+            for candidate in actions.iter().skip(1) {
+                if candidate.1 > action.1 {
+                    action = *candidate;
+                }
+            }
+            actions_taken.insert((t, state.0, state.1, state.2), action.0);
+        })?;
+        if let Some(v_all) = v_all.as_deref_mut() {
+            rust::value_function::record_period_values(
+                v_all,
+                t,
+                v.iter().map(|entry| (*entry.key(), *entry.value())),
+            );
+        }
+    }
+    Ok(actions_taken)
+}
@@ -0,0 +1,47 @@
+// Lets long solves run with the GIL released while still responding to Ctrl-C
+use pyo3::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+// Number of states solved between checks for pending signals
+pub const SIGNAL_CHECK_INTERVAL: usize = 64;
+
+// How often the calling thread checks for pending signals while a parallel loop runs
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Briefly reacquire the GIL to run any pending Python signal handlers.
+// Returns the handler's error (a KeyboardInterrupt for Ctrl-C) so the solve can stop.
+// Python only runs signal handlers on its main thread, so this has to be called from the thread that released the GIL.
+pub fn check_signals() -> PyResult<()> {
+    Python::with_gil(|py| py.check_signals())
+}
+
+// Runs work on a scoped thread, where it can use the rayon pool, while the calling thread checks for signals and
+// calls poll (e.g. to report progress) every POLL_INTERVAL. When either fails the cancel flag given to work is set,
+// work should then return early, and the error is returned once it has.
+pub fn run_interruptible<T: Send>(
+    work: impl FnOnce(&AtomicBool) -> T + Send,
+    mut poll: impl FnMut() -> PyResult<()>,
+) -> PyResult<T> {
+    let cancel = AtomicBool::new(false);
+    let (done, finished) = mpsc::channel();
+    std::thread::scope(|scope| {
+        let worker = scope.spawn(|| {
+            let out = work(&cancel);
+            // The receiver only goes away once the worker is joined
+            let _ = done.send(());
+            out
+        });
+        let mut result = Ok(());
+        while let Err(mpsc::RecvTimeoutError::Timeout) = finished.recv_timeout(POLL_INTERVAL) {
+            result = check_signals().and_then(|_| poll());
+            if result.is_err() {
+                cancel.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+        let out = worker.join().expect("Solver thread panicked");
+        result.map(|_| out)
+    })
+}
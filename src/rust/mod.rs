@@ -1,4 +1,13 @@
+pub mod arrays;
+pub mod bounds;
+pub mod diagnostics;
 pub mod distributions;
+pub mod environment;
+pub mod evaluation;
+pub mod interrupt;
+pub mod optimal;
 pub mod policy_contructor;
+pub mod progress;
+pub mod stationary;
 pub mod value_function;
 pub mod policies;
\ No newline at end of file
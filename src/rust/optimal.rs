@@ -0,0 +1,127 @@
+// Parallel backward induction for the optimal policy
+use crate::rust;
+use crate::rust::policies::{Action, State};
+use dashmap::DashMap;
+use pyo3::prelude::*;
+use std::collections::HashMap;
+
+// DFW threshold chosen in each period and state, keyed by (t, wh, sa, sb)
+pub type DfwThresholds = DashMap<(usize, usize, usize, usize), usize>;
+
+// Solves for the optimal policy over the decision periods 1..=periods.
+// v holds the terminal cost (period periods + 1) on entry and the first period's value function on exit.
+// When v_all is given every period's value function is recorded in it.
+// Returns the optimal action in every period and state, keyed by (t, wh, sa, sb).
+// When dfw_thresholds is given the DFW threshold is chosen with the action in every period and state and recorded in it,
+// otherwise the model's fixed dfw_threshold applies.
+pub fn solve_optimal_policy(
+    policy_constructor: &rust::policy_contructor::OptimalPolicy,
+    periods: usize,
+    v: &DashMap<State, f64>,
+    mut v_all: Option<&mut HashMap<(usize, usize, usize, usize), f64>>,
+    dfw_thresholds: Option<&DfwThresholds>,
+    progress: &rust::progress::ProgressTracker,
+) -> PyResult<DashMap<(usize, usize, usize, usize), Action>> {
+    let store_expectation = policy_constructor.expectation_all_stores();
+    let warehouse_expectation = policy_constructor.expectation_all_warehouse();
+    let rationed_expectation = match dfw_thresholds {
+        Some(_) => rust::value_function::rationed_expectations(policy_constructor)?,
+        None => HashMap::new(),
+    };
+    let action_space = policy_constructor.construct_action_space();
+    let optimal_pol: DashMap<(usize, usize, usize, usize), Action> = DashMap::new();
+
+    // Iterate through periods
+    for t in (1..=periods).rev() {
+        // Save previous iteration (v_t+1)
+        let v_plus_1_hm = v
+            .clone()
+            .into_iter()
+            .collect::<HashMap<(usize, usize, usize), f64>>();
+
+        // Iterate through all states
+        let state_space: Vec<(usize, usize, usize)> = policy_constructor
+            .construct_state_space_iterator()
+            .collect();
+        progress.solve_period(t, &state_space, |state| {
+            let state = (state.0, state.1, state.2);
+            // Calculate the value function
+            let (action, v_t_x) = match dfw_thresholds {
+                Some(dfw_thresholds) => {
+                    let (action, dfw_threshold, v_t_x) =
+                        rust::value_function::value_function_optimal_pol_rationed(
+                            policy_constructor,
+                            state,
+                            &v_plus_1_hm,
+                            &action_space[&state],
+                            &rationed_expectation,
+                        );
+                    dfw_thresholds.insert((t, state.0, state.1, state.2), dfw_threshold);
+                    (action, v_t_x)
+                }
+                None => rust::value_function::value_function_optimal_pol(
+                    policy_constructor,
+                    state,
+                    &v_plus_1_hm,
+                    &action_space[&state],
+                    &store_expectation,
+                    &warehouse_expectation,
+                ),
+            };
+            // Update the value function
+            v.insert(state, v_t_x);
+            // Store the optimal policy
+            optimal_pol.insert((t, state.0, state.1, state.2), action);
+        })?;
+        if let Some(v_all) = v_all.as_deref_mut() {
+            rust::value_function::record_period_values(
+                v_all,
+                t,
+                v.iter().map(|entry| (*entry.key(), *entry.value())),
+            );
+        }
+    }
+    Ok(optimal_pol)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::OptimalPolicy;
+
+    #[test]
+    fn policy_covers_the_last_decision_period() {
+        pyo3::prepare_freethreaded_python();
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let periods = 2;
+        let terminal = rust::value_function::terminal_cost(&policy_constructor, Some((1.0, 1.0, 1.0)), None).unwrap();
+        let terminal_hm: HashMap<State, f64> = terminal.iter().map(|entry| (*entry.key(), *entry.value())).collect();
+        let mut v_all = HashMap::new();
+        let progress = rust::progress::ProgressTracker::new(None, 0, 0);
+        let optimal_pol =
+            solve_optimal_policy(&policy_constructor, periods, &terminal, Some(&mut v_all), None, &progress).unwrap();
+
+        let state_space: Vec<State> = policy_constructor.construct_state_space_iterator().collect();
+        assert_eq!(optimal_pol.len(), periods * state_space.len());
+        let action_space = policy_constructor.construct_action_space();
+        let store_expectation = policy_constructor.expectation_all_stores();
+        let warehouse_expectation = policy_constructor.expectation_all_warehouse();
+        for state in state_space {
+            for t in 1..=periods {
+                assert!(optimal_pol.contains_key(&(t, state.0, state.1, state.2)), "period {} state {:?}", t, state);
+            }
+            // The last decision period is solved against the terminal cost
+            let (action, value) = rust::value_function::value_function_optimal_pol(
+                &policy_constructor,
+                state,
+                &terminal_hm,
+                &action_space[&state],
+                &store_expectation,
+                &warehouse_expectation,
+            );
+            assert_eq!(optimal_pol.get(&(periods, state.0, state.1, state.2)).map(|a| *a), Some(action));
+            assert_eq!(v_all[&(periods, state.0, state.1, state.2)], value);
+        }
+    }
+}
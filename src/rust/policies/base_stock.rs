@@ -1,9 +1,68 @@
+use crate::rust::policies::{OrderingPolicy, State};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
 use std::cmp::max;
 use std::cmp::min;
 
+// How scarce warehouse stock is split between the store requests, mean demands are (store A, store B)
+#[derive(Clone, Copy)]
+pub enum AllocationRule {
+    // One unit at a time to the larger remaining request, ties to store B
+    LargestRequest,
+    // In proportion to the requests
+    ProportionalRequest,
+    // In proportion to mean demand, capped at the requests
+    ProportionalMean((f64, f64)),
+    // Fill the request of the given store (1 or 2) first
+    Priority(usize),
+    // Equalise the periods of mean demand each store's stock covers after allocation
+    BalancedRunOut((f64, f64)),
+}
+
+impl AllocationRule {
+    // Allocation rule can be 'R' - larger remaining request, 'P' - proportional to request, 'M' - proportional to mean demand,
+    // 'A' or 'B' - priority to that store and 'E' - balanced run-out
+    pub fn from_code(code: char, store_means: (f64, f64)) -> PyResult<Self> {
+        match code {
+            'R' => Ok(AllocationRule::LargestRequest),
+            'P' => Ok(AllocationRule::ProportionalRequest),
+            'M' => Ok(AllocationRule::ProportionalMean(store_means)),
+            'A' => Ok(AllocationRule::Priority(1)),
+            'B' => Ok(AllocationRule::Priority(2)),
+            'E' => Ok(AllocationRule::BalancedRunOut(store_means)),
+            _ => Err(PyValueError::new_err("Allocation rule not recognised")),
+        }
+    }
+}
+
+// Regular base-stock ordering with optional store order caps, base_stock_vals is (WH, SA, SB)
+pub struct RegularBaseStock {
+    warehouse_bs: usize,
+    store_bs: (usize, usize),
+    order_cap: Option<(usize, usize)>,
+    allocation: AllocationRule,
+}
+
+impl RegularBaseStock {
+    pub fn new(base_stock_vals: (usize, usize, usize), order_cap: Option<(usize, usize)>, allocation: AllocationRule) -> Self {
+        RegularBaseStock {
+            warehouse_bs: base_stock_vals.0,
+            store_bs: (base_stock_vals.1, base_stock_vals.2),
+            order_cap,
+            allocation,
+        }
+    }
+}
+
+impl OrderingPolicy for RegularBaseStock {
+    fn order(&self, _t: usize, state: State) -> (usize, usize, usize) {
+        regular_base_stock(state, self.warehouse_bs, self.store_bs, self.order_cap, &self.allocation)
+    }
+}
+
 // Implement a base-stock policy for rust
 // Note there is a lead-time of 1 for the store and warehouse.
-pub fn regular_base_stock(state: (usize, usize, usize), warehouse_bs: usize, store_bs: (usize, usize), order_cap: Option<(usize,usize)>) -> (usize, usize, usize) {
+pub fn regular_base_stock(state: (usize, usize, usize), warehouse_bs: usize, store_bs: (usize, usize), order_cap: Option<(usize,usize)>, allocation: &AllocationRule) -> (usize, usize, usize) {
     let order_caps = order_cap.unwrap_or(store_bs);
     // Remember store_bs.0 is the first store and store_bs.1 is the second stores order up to
     let mut desired_sa = max(store_bs.0 as isize - state.1 as isize, 0) as usize;
@@ -16,7 +75,7 @@ pub fn regular_base_stock(state: (usize, usize, usize), warehouse_bs: usize, sto
 
     // if the desired is more than the warehouse level then we need to allocate
     if state.0 < desired_sa + desired_sb {
-        (desired_sa, desired_sb) = allocate_stock(state.0, desired_sa, desired_sb);
+        (desired_sa, desired_sb) = allocate_stock(state, desired_sa, desired_sb, allocation);
     } 
     (wh_order, desired_sa, desired_sb)
 }
@@ -26,15 +85,37 @@ pub fn regular_base_stock(state: (usize, usize, usize), warehouse_bs: usize, sto
 //     unimplemented!("Echelon base stock policy is not implemented yet");
 // }
 
-pub fn allocate_stock(wh_state: usize, sa_request: usize, sb_request: usize) -> (usize, usize) {
+// Splits the warehouse stock state.0 between requests it cannot cover one unit at a time, each unit goes to the store
+// furthest behind under the allocation rule. Store levels in state are after transhipments.
+pub fn allocate_stock(state: State, sa_request: usize, sb_request: usize, allocation: &AllocationRule) -> (usize, usize) {
     let mut sa_alloc = 0;
     let mut sb_alloc = 0;
-    let mut wh_available = wh_state;
+    let mut wh_available = min(state.0, sa_request + sb_request);
 
     while wh_available > 0 {
         let current_sa_request = sa_request - sa_alloc;
         let current_sb_request = sb_request - sb_alloc;
-        if current_sa_request > current_sb_request {
+        let to_a = if current_sa_request == 0 {
+            false
+        } else if current_sb_request == 0 {
+            true
+        } else {
+            match allocation {
+                AllocationRule::LargestRequest => current_sa_request > current_sb_request,
+                // Smaller share of its request so far, compared without dividing
+                AllocationRule::ProportionalRequest => {
+                    sa_alloc * sb_request < sb_alloc * sa_request
+                }
+                AllocationRule::ProportionalMean(means) => {
+                    (sa_alloc as f64) * means.1 < (sb_alloc as f64) * means.0
+                }
+                AllocationRule::Priority(store) => *store == 1,
+                AllocationRule::BalancedRunOut(means) => {
+                    ((state.1 + sa_alloc) as f64) * means.1 < ((state.2 + sb_alloc) as f64) * means.0
+                }
+            }
+        };
+        if to_a {
             sa_alloc += 1;
         } else {
             sb_alloc += 1;
@@ -42,4 +123,4 @@ pub fn allocate_stock(wh_state: usize, sa_request: usize, sb_request: usize) ->
         wh_available -= 1;
     }
     (sa_alloc,sb_alloc)
-}
\ No newline at end of file
+}
@@ -0,0 +1,56 @@
+// Policies given as a Python callable f(t, wh, sa, sb) -> action
+use crate::rust;
+use pyo3::prelude::*;
+use std::collections::HashMap;
+
+// Calls the policy once for every period and state, PolicyInput::into_table checks the actions like any other table.
+// The resulting table is what gets evaluated so the callable is never called twice for the same (t, state).
+pub fn callable_policy_table(
+    policy_constructor: &rust::policy_contructor::OptimalPolicy,
+    periods: usize,
+    policy: &Bound<'_, PyAny>,
+) -> PyResult<rust::arrays::PolicyTable> {
+    let mut table = HashMap::new();
+    for t in 1..=periods {
+        for state in policy_constructor.construct_state_space_iterator() {
+            let action: (usize, usize, usize, usize, usize) = policy
+                .call1((t, state.0, state.1, state.2))?
+                .extract()?;
+            table.insert((t, state.0, state.1, state.2), action);
+        }
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::arrays::PolicyInput;
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::OptimalPolicy;
+
+    #[test]
+    fn callable_is_tabled_for_every_period_and_state() {
+        pyo3::prepare_freethreaded_python();
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let periods = 2;
+        Python::with_gil(|py| {
+            // Tranships one unit from A to B when B is empty, and orders one unit for a warehouse with room in period 1
+            let policy = py
+                .eval_bound("lambda t, wh, sa, sb: (int(t == 1 and wh < 5), 0, 0, int(sa > 0 and sb == 0), 0)", None, None)
+                .unwrap();
+            let table = PolicyInput::Callable(policy).into_table(&policy_constructor, periods).unwrap();
+            let state_space: Vec<_> = policy_constructor.construct_state_space_iterator().collect();
+            assert_eq!(table.len(), periods * state_space.len());
+            assert_eq!(table[&(1, 0, 2, 0)], (1, 0, 0, 1, 0));
+            assert_eq!(table[&(2, 0, 2, 0)], (0, 0, 0, 1, 0));
+            assert_eq!(table[&(2, 3, 0, 0)], (0, 0, 0, 0, 0));
+
+            // Actions outside the action space are caught like those of any other table
+            let infeasible = py.eval_bound("lambda t, wh, sa, sb: (0, 0, 0, 1, 0)", None, None).unwrap();
+            assert!(PolicyInput::Callable(infeasible).into_table(&policy_constructor, periods).is_err());
+            let not_an_action = py.eval_bound("lambda t, wh, sa, sb: None", None, None).unwrap();
+            assert!(callable_policy_table(&policy_constructor, periods, &not_an_action).is_err());
+        });
+    }
+}
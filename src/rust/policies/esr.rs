@@ -1,8 +1,57 @@
 use crate::rust;
+use crate::rust::policies::{State, TranshipmentPolicy};
 use std::collections::HashMap;
 
 use std::usize;
 
+// One step ahead (expected lost sales, first stage lost sales) for non-terminal and terminal periods,
+// keyed by (store level, store number, order-up-to level)
+pub type OneStepAheadExpectations = (
+    HashMap<(usize, usize, usize), (f64, f64)>,
+    HashMap<(usize, usize, usize), (f64, f64)>,
+);
+
+// Expected Shortage Reduction, the one step ahead expectations are built in setup
+pub struct Esr<'a> {
+    policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+    store_bs: (usize, usize),
+    one_step_ahead_expectations: OneStepAheadExpectations,
+    final_period: usize,
+}
+
+impl<'a> Esr<'a> {
+    pub fn new(
+        policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+        store_bs: (usize, usize),
+    ) -> Self {
+        Esr {
+            policy_constructor,
+            store_bs,
+            one_step_ahead_expectations: (HashMap::new(), HashMap::new()),
+            final_period: 0,
+        }
+    }
+}
+
+impl TranshipmentPolicy for Esr<'_> {
+    fn setup(&mut self, periods: usize) {
+        self.one_step_ahead_expectations = self.policy_constructor.all_one_step_ahead_out();
+        self.final_period = periods;
+    }
+
+    fn transhipment(&self, t: usize, state: State) -> (usize, usize) {
+        calculate_esr(
+            self.policy_constructor,
+            &self.one_step_ahead_expectations,
+            state.1,
+            state.2,
+            self.store_bs.0,
+            self.store_bs.1,
+            t == self.final_period,
+        )
+    }
+}
+
 pub fn calculate_esr(
     policy_contructor: &rust::policy_contructor::OptimalPolicy,
     expecation_all_one_step_ahead_and_terminal: &(
@@ -46,8 +95,9 @@ pub fn calculate_esr(
             expecation_all_one_step_ahead[&((state_b), 2, base_stock_b)],
             expecation_all_one_step_ahead[&((state_b + 1), 2, base_stock_b)],
         );
-        let alpha_a = f_vals_store_a.0 .0 - f_vals_store_a.1 .0;
-        let alpha_b = f_vals_store_b.0 .0 - f_vals_store_b.1 .0;
+        // Extra lost sales from giving up a unit, costed at each store's c_u_s
+        let alpha_a = policy_contructor.c_u_s.0 * (f_vals_store_a.0 .0 - f_vals_store_a.1 .0);
+        let alpha_b = policy_contructor.c_u_s.1 * (f_vals_store_b.0 .0 - f_vals_store_b.1 .0);
         // Return the smallest value as the relevant source store
         if alpha_a < alpha_b {
             1
@@ -78,6 +128,9 @@ pub fn calculate_esr(
     );
 
     let mut transhipments_still_occur = true;
+    // Lost sales cost saved by the units moved so far, net of their per-unit transhipment cost
+    let mut net_saving = 0.0;
+    let source_start = source_info.0;
 
     while transhipments_still_occur {
         // Check we are not at the state-space boundary
@@ -103,7 +156,12 @@ pub fn calculate_esr(
                     desintation_info.2,
                 )]
                     .0;
-            if delta - alpha > policy_contructor.c_ts / policy_contructor.c_u_s {
+            // Lost sales saved at the destination less those added at the source, each at its own store's c_u_s
+            let c_u_s_source = policy_contructor.store_params(source_info.1).2;
+            let c_u_s_destination = policy_contructor.store_params(desintation_info.1).2;
+            let saving = c_u_s_destination * delta - c_u_s_source * alpha;
+            let unit_cost = policy_contructor.unit_transhipment_cost(source_info.1);
+            if saving > unit_cost {
                 // check secondary condition
                 if (expecation_all_one_step_ahead
                     [&(desintation_info.0, desintation_info.1, desintation_info.2)]
@@ -132,9 +190,14 @@ pub fn calculate_esr(
                 // Make transfer
                 source_info.0 -= 1;
                 desintation_info.0 += 1;
+                net_saving += saving - unit_cost;
             }
         }
     }
+    // Only ship if the units moved also cover the fixed cost of a shipment
+    if source_info.0 != source_start && net_saving <= policy_contructor.c_ts_fixed {
+        return (0, 0);
+    }
     if source_info.1 == 1 {
         return (state_a - source_info.0, 0);
     } else {
@@ -0,0 +1,81 @@
+use crate::rust;
+use crate::rust::policies::{State, TranshipmentPolicy};
+
+// Protected and trigger levels of the hold-back policy, each as (store A, store B)
+pub struct HoldBackLevels {
+    pub hold_back: (usize, usize),
+    pub trigger: (usize, usize),
+}
+
+// Hold-back transhipment, a store below its trigger level is brought back up to it from the other store,
+// which only gives up stock above its hold-back level. If both stores could receive, the one further below its trigger does.
+pub struct HoldBack<'a> {
+    policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+    levels: HoldBackLevels,
+}
+
+impl<'a> HoldBack<'a> {
+    pub fn new(
+        policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+        levels: HoldBackLevels,
+    ) -> Self {
+        HoldBack {
+            policy_constructor,
+            levels,
+        }
+    }
+}
+
+impl TranshipmentPolicy for HoldBack<'_> {
+    fn transhipment(&self, _t: usize, state: State) -> (usize, usize) {
+        let (hold_back, trigger) = (self.levels.hold_back, self.levels.trigger);
+        let shortfall = (
+            trigger.0.saturating_sub(state.1),
+            trigger.1.saturating_sub(state.2),
+        );
+        // Units each store would receive, capped by the sender's stock above hold-back and the state space
+        let to_a = shortfall
+            .0
+            .min(state.2.saturating_sub(hold_back.1))
+            .min(self.policy_constructor.max_sa - 1 - state.1);
+        let to_b = shortfall
+            .1
+            .min(state.1.saturating_sub(hold_back.0))
+            .min(self.policy_constructor.max_sb - 1 - state.2);
+        if to_a > 0 && (to_b == 0 || shortfall.0 >= shortfall.1) {
+            (0, to_a)
+        } else {
+            (to_b, 0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::OptimalPolicy;
+
+    #[test]
+    fn stores_below_trigger_are_topped_up_above_hold_back() {
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let hold_back = HoldBack::new(&policy_constructor, HoldBackLevels { hold_back: (1, 1), trigger: (2, 2) });
+        assert_eq!(hold_back.transhipment(1, (0, 0, 3)), (0, 2));
+        assert_eq!(hold_back.transhipment(1, (0, 3, 0)), (2, 0));
+        // The sender only gives up what it holds above its hold-back level
+        assert_eq!(hold_back.transhipment(1, (0, 0, 2)), (0, 1));
+        assert_eq!(hold_back.transhipment(1, (0, 0, 1)), (0, 0));
+        // Nothing moves when both stores are at or above their triggers
+        assert_eq!(hold_back.transhipment(1, (0, 3, 3)), (0, 0));
+        assert_eq!(hold_back.transhipment(1, (0, 2, 2)), (0, 0));
+    }
+
+    #[test]
+    fn store_further_below_its_trigger_receives() {
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let hold_back = HoldBack::new(&policy_constructor, HoldBackLevels { hold_back: (0, 0), trigger: (3, 3) });
+        // Store A is two short and store B one short, so A receives
+        assert_eq!(hold_back.transhipment(1, (0, 1, 2)), (0, 2));
+        assert_eq!(hold_back.transhipment(1, (0, 2, 1)), (2, 0));
+    }
+}
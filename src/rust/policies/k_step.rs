@@ -0,0 +1,195 @@
+use crate::rust;
+use crate::rust::policies::base_stock::{AllocationRule, RegularBaseStock};
+use crate::rust::policies::{Action, OrderingPolicy, Policy, State};
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+// k step lookahead, picks transhipments and store orders together by the expected cost of this period plus the next
+// k periods, in which the base-stock policy orders without transhipping. The warehouse orders up to the base-stock level.
+// With an order cap no store order, this period's or the tail's, goes past it.
+// One step matches the horizon of calculate_lookahead, an order only costs or saves in the period after it is placed.
+// Near the end of the horizon the lookahead only runs to the last decision period, followed by the terminal cost.
+pub struct KStepLookahead<'a> {
+    policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+    steps: usize,
+    base_stock: RegularBaseStock,
+    warehouse_bs: usize,
+    order_cap: Option<(usize, usize)>,
+    // Best action in every state when looking m periods ahead, for m in 0..=steps
+    actions: Vec<HashMap<State, Action>>,
+    // Cost of the stock left at the end of the horizon, None for zero terminal cost
+    terminal_cost: Option<HashMap<State, f64>>,
+    // As actions, when the m periods ahead run to the end of the horizon and the terminal cost follows them.
+    // Empty without a terminal cost, actions covers that case
+    horizon_actions: Vec<HashMap<State, Action>>,
+    final_period: usize,
+}
+
+impl<'a> KStepLookahead<'a> {
+    pub fn new(
+        policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+        steps: usize,
+        base_stock_vals: (usize, usize, usize),
+        order_cap: Option<(usize, usize)>,
+        allocation: AllocationRule,
+        terminal_cost: Option<HashMap<State, f64>>,
+    ) -> Self {
+        KStepLookahead {
+            policy_constructor,
+            steps,
+            base_stock: RegularBaseStock::new(base_stock_vals, order_cap, allocation),
+            warehouse_bs: base_stock_vals.0,
+            order_cap,
+            actions: Vec::new(),
+            terminal_cost,
+            horizon_actions: Vec::new(),
+            final_period: 0,
+        }
+    }
+
+    // Transhipments and store orders of the action space, within the order cap, with the base-stock warehouse order
+    fn candidates(&self, state: State) -> Vec<Action> {
+        let policy_constructor = self.policy_constructor;
+        policy_constructor
+            .generate_action_space(state)
+            .into_iter()
+            .filter(|action| {
+                let wh_after_orders = state.0 - action.1 - action.2;
+                let wh_order = self
+                    .warehouse_bs
+                    .saturating_sub(wh_after_orders)
+                    .min(policy_constructor.max_wh - 1 - wh_after_orders);
+                let within_cap = self
+                    .order_cap
+                    .is_none_or(|(cap_a, cap_b)| action.1 <= cap_a && action.2 <= cap_b);
+                action.0 == wh_order && within_cap
+            })
+            .collect()
+    }
+
+    // Best action in every state for m in 0..=steps periods ahead, when the cost after the lookahead is given by after
+    fn lookahead_actions(
+        &self,
+        state_space: &[State],
+        after: HashMap<State, f64>,
+        store_expectation: &HashMap<State, f64>,
+        warehouse_expectation: &HashMap<State, f64>,
+    ) -> Vec<HashMap<State, Action>> {
+        let policy_constructor = self.policy_constructor;
+        // Cost of m periods of the base-stock tail from each state, starting with the cost after the lookahead
+        let mut tail = after;
+        let mut actions = Vec::with_capacity(self.steps + 1);
+        for m in 0..=self.steps {
+            let best: HashMap<State, Action> = state_space
+                .par_iter()
+                .map(|state| {
+                    let mut best_action: Option<(Action, f64)> = None;
+                    for action in self.candidates(*state) {
+                        let cost = rust::value_function::value_function_pol_eval(
+                            policy_constructor,
+                            *state,
+                            &tail,
+                            action,
+                            store_expectation,
+                            warehouse_expectation,
+                        );
+                        if best_action.is_none() || cost < best_action.unwrap().1 {
+                            best_action = Some((action, cost));
+                        }
+                    }
+                    (*state, best_action.unwrap().0)
+                })
+                .collect();
+            actions.push(best);
+            if m == self.steps {
+                break;
+            }
+            tail = state_space
+                .par_iter()
+                .map(|state| {
+                    let (wh_order, sa_order, sb_order) = self.base_stock.order(0, *state);
+                    let cost = rust::value_function::value_function_pol_eval(
+                        policy_constructor,
+                        *state,
+                        &tail,
+                        (wh_order, sa_order, sb_order, 0, 0),
+                        store_expectation,
+                        warehouse_expectation,
+                    );
+                    (*state, cost)
+                })
+                .collect();
+        }
+        actions
+    }
+}
+
+impl Policy for KStepLookahead<'_> {
+    fn setup(&mut self, periods: usize) {
+        let policy_constructor = self.policy_constructor;
+        let store_expectation = policy_constructor.expectation_all_stores();
+        let warehouse_expectation = policy_constructor.expectation_all_warehouse();
+        let state_space: Vec<State> = policy_constructor.construct_state_space_iterator().collect();
+        self.final_period = periods;
+
+        // Nothing is counted beyond a lookahead that stops before the end of the horizon
+        let zero = state_space.iter().map(|state| (*state, 0.0)).collect();
+        self.actions = self.lookahead_actions(&state_space, zero, &store_expectation, &warehouse_expectation);
+        self.horizon_actions = match &self.terminal_cost {
+            Some(terminal_cost) => self.lookahead_actions(
+                &state_space,
+                terminal_cost.clone(),
+                &store_expectation,
+                &warehouse_expectation,
+            ),
+            None => Vec::new(),
+        };
+    }
+
+    fn action(&self, t: usize, state: State) -> Action {
+        let periods_left = self.final_period.saturating_sub(t);
+        if periods_left <= self.steps && !self.horizon_actions.is_empty() {
+            return self.horizon_actions[periods_left][&state];
+        }
+        self.actions[self.steps.min(periods_left)][&state]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::OptimalPolicy;
+
+    #[test]
+    fn lookahead_to_the_horizon_matches_the_optimal_value() {
+        pyo3::prepare_freethreaded_python();
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        // A salvage value for store stock, so the orders of the last decision period only pay off through the terminal cost
+        let terminal = rust::value_function::terminal_cost(&policy_constructor, Some((0.0, -1.0, -1.0)), None).unwrap();
+        let terminal_hm: HashMap<State, f64> = terminal.iter().map(|entry| (*entry.key(), *entry.value())).collect();
+        let progress = rust::progress::ProgressTracker::new(None, 0, 0);
+        let periods = 1;
+
+        let v_optimal = terminal.clone();
+        rust::optimal::solve_optimal_policy(&policy_constructor, periods, &v_optimal, None, None, &progress).unwrap();
+        // Warehouse stock arriving after the last decision period has no terminal cost, so the DP gains nothing by
+        // ordering and a warehouse base-stock level of zero loses nothing
+        let mut k_step = KStepLookahead::new(
+            &policy_constructor,
+            2,
+            (0, 3, 3),
+            None,
+            AllocationRule::LargestRequest,
+            Some(terminal_hm),
+        );
+        k_step.setup(periods);
+        let v_k_step = terminal.clone();
+        rust::evaluation::evaluate_policy(&policy_constructor, &k_step, periods, &v_k_step, None, &progress).unwrap();
+
+        for state in policy_constructor.construct_state_space_iterator() {
+            let (optimal, k_step) = (*v_optimal.get(&state).unwrap(), *v_k_step.get(&state).unwrap());
+            assert!((optimal - k_step).abs() < 1e-9, "state {:?}: optimal {} k step {}", state, optimal, k_step);
+        }
+    }
+}
@@ -1,8 +1,267 @@
 use crate::rust;
+use crate::rust::policies::base_stock::{allocate_stock, AllocationRule};
+use crate::rust::policies::{Action, Policy, State};
 use std::cmp::max;
 use std::collections::HashMap;
 use std::usize;
 
+// One step lookahead expectations for non-terminal and terminal periods, keyed by (wh, store level, store number)
+type LookaheadExpectations = (
+    HashMap<(usize, usize, usize), (f64, f64, f64)>,
+    HashMap<(usize, usize, usize), (f64, f64, f64)>,
+);
+
+// One step lookahead choosing transhipments and store orders, warehouse orders up to warehouse_bs.
+// Without an allocation rule each store may order up to its mean demand share of the warehouse,
+// with one each store may order up to the whole warehouse and the rule rations the orders when short.
+// Either way no store orders past what it can hold. With optimise_warehouse the orders come from MarginalOrdering
+// instead, the transhipments are still judged with the lookahead's own store orders.
+pub struct Lookahead<'a> {
+    policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+    warehouse_bs: usize,
+    store_means: (f64, f64),
+    allocation: Option<AllocationRule>,
+    optimise_warehouse: bool,
+    marginal_ordering: Option<MarginalOrdering>,
+    one_step_lookahead_expectations: LookaheadExpectations,
+    final_period: usize,
+}
+
+impl<'a> Lookahead<'a> {
+    pub fn new(
+        policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+        warehouse_bs: usize,
+        store_means: (f64, f64),
+        allocation: Option<AllocationRule>,
+        optimise_warehouse: bool,
+    ) -> Self {
+        Lookahead {
+            policy_constructor,
+            warehouse_bs,
+            store_means,
+            allocation,
+            optimise_warehouse,
+            marginal_ordering: None,
+            one_step_lookahead_expectations: (HashMap::new(), HashMap::new()),
+            final_period: 0,
+        }
+    }
+}
+
+impl Policy for Lookahead<'_> {
+    fn setup(&mut self, periods: usize) {
+        self.one_step_lookahead_expectations = self
+            .policy_constructor
+            .all_one_step_ahead_la(
+                self.store_means.0,
+                self.store_means.1,
+                self.allocation.is_none() && !self.optimise_warehouse,
+            );
+        self.marginal_ordering = self
+            .optimise_warehouse
+            .then(|| MarginalOrdering::new(self.policy_constructor));
+        self.final_period = periods;
+    }
+
+    fn action(&self, t: usize, state: State) -> Action {
+        let Some(marginal_ordering) = &self.marginal_ordering else {
+            return calculate_lookahead(
+                self.policy_constructor,
+                &self.one_step_lookahead_expectations,
+                state,
+                self.warehouse_bs,
+                t == self.final_period,
+                self.allocation.as_ref(),
+            );
+        };
+        // Keep the lookahead's transhipments and replace its orders
+        let (_, _, _, t_a_to_b, t_b_to_a) = calculate_lookahead(
+            self.policy_constructor,
+            &self.one_step_lookahead_expectations,
+            state,
+            0,
+            t == self.final_period,
+            None,
+        );
+        let (wh_order, st_a_order, st_b_order) = marginal_ordering.orders(
+            self.policy_constructor,
+            (
+                state.0,
+                state.1 - t_a_to_b + t_b_to_a,
+                state.2 - t_b_to_a + t_a_to_b,
+            ),
+            self.final_period.saturating_sub(t),
+        );
+        (wh_order, st_a_order, st_b_order, t_a_to_b, t_b_to_a)
+    }
+}
+
+// One step lookahead for store orders only, no transhipments, ordering as Lookahead does
+pub struct LookaheadNoTranshipment<'a> {
+    policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+    warehouse_bs: usize,
+    store_means: (f64, f64),
+    allocation: Option<AllocationRule>,
+    optimise_warehouse: bool,
+    marginal_ordering: Option<MarginalOrdering>,
+    one_step_lookahead_expectations: LookaheadExpectations,
+    final_period: usize,
+}
+
+impl<'a> LookaheadNoTranshipment<'a> {
+    pub fn new(
+        policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+        warehouse_bs: usize,
+        store_means: (f64, f64),
+        allocation: Option<AllocationRule>,
+        optimise_warehouse: bool,
+    ) -> Self {
+        LookaheadNoTranshipment {
+            policy_constructor,
+            warehouse_bs,
+            store_means,
+            allocation,
+            optimise_warehouse,
+            marginal_ordering: None,
+            one_step_lookahead_expectations: (HashMap::new(), HashMap::new()),
+            final_period: 0,
+        }
+    }
+}
+
+impl Policy for LookaheadNoTranshipment<'_> {
+    fn setup(&mut self, periods: usize) {
+        self.one_step_lookahead_expectations = self
+            .policy_constructor
+            .all_one_step_ahead_la(
+                self.store_means.0,
+                self.store_means.1,
+                self.allocation.is_none() && !self.optimise_warehouse,
+            );
+        self.marginal_ordering = self
+            .optimise_warehouse
+            .then(|| MarginalOrdering::new(self.policy_constructor));
+        self.final_period = periods;
+    }
+
+    fn action(&self, t: usize, state: State) -> Action {
+        if let Some(marginal_ordering) = &self.marginal_ordering {
+            let order = marginal_ordering.orders(
+                self.policy_constructor,
+                state,
+                self.final_period.saturating_sub(t),
+            );
+            return (order.0, order.1, order.2, 0, 0);
+        }
+        let order = calculate_lookahead_no_transhipment(
+            &self.one_step_lookahead_expectations,
+            state,
+            self.warehouse_bs,
+            t == self.final_period,
+            self.allocation.as_ref(),
+        );
+        (order.0, order.1, order.2, 0, 0)
+    }
+}
+
+// Expected cost of a store in the period after its order, keyed by (store level, order, store number)
+type OrderCosts = HashMap<(usize, usize, usize), f64>;
+
+// Orders of the lookahead policies with optimise_warehouse, replacing the mean demand split and the fixed warehouse level.
+// Warehouse stock goes to the stores a unit at a time, each unit to the store whose expected cost in the period after
+// the order falls the most, until neither store gains. The warehouse then orders the echelon stock (warehouse plus both
+// stores) up to the level minimising h_w per unit held against the largest of c_u_s and c_u_w per unit short, over the
+// demand at both stores and online in the periods an order placed now can serve: this one, the warehouse lead time and
+// the store lead time after it. Near the end of the horizon it covers fewer periods and the last decision period orders nothing.
+pub struct MarginalOrdering {
+    // Non-terminal then terminal
+    order_costs: (OrderCosts, OrderCosts),
+    // Echelon order-up-to levels covering two and three periods of demand
+    order_up_to: (usize, usize),
+}
+
+impl MarginalOrdering {
+    pub fn new(policy_constructor: &rust::policy_contructor::OptimalPolicy) -> Self {
+        let mut order_costs = (HashMap::new(), HashMap::new());
+        for (store, max_s) in [(1, policy_constructor.max_sa), (2, policy_constructor.max_sb)] {
+            for x in 0..max_s {
+                for q in 0..max_s - x {
+                    order_costs.0.insert(
+                        (x, q, store),
+                        policy_constructor.lookahead_q_expectation(x, q as f64, store, false),
+                    );
+                    order_costs.1.insert(
+                        (x, q, store),
+                        policy_constructor.lookahead_q_expectation(x, q as f64, store, true),
+                    );
+                }
+            }
+        }
+
+        let c_u = f64::max(
+            f64::max(policy_constructor.c_u_s.0, policy_constructor.c_u_s.1),
+            policy_constructor.c_u_w,
+        );
+        let fractile = c_u / (c_u + policy_constructor.h_w);
+        let period_demand = rust::bounds::convolve(
+            &rust::bounds::convolve(&policy_constructor.da_pmf, &policy_constructor.db_pmf),
+            &policy_constructor.dw_pmf,
+        );
+        let level = |periods: usize| {
+            rust::bounds::quantile(
+                &rust::bounds::repeat_convolve(&period_demand, periods),
+                fractile,
+            )
+        };
+        MarginalOrdering {
+            order_costs,
+            order_up_to: (level(2), level(3)),
+        }
+    }
+
+    // (wh_order, sa_order, sb_order) in a post-transhipment state with periods_left decision periods after this one
+    pub fn orders(
+        &self,
+        policy_constructor: &rust::policy_contructor::OptimalPolicy,
+        state: State,
+        periods_left: usize,
+    ) -> (usize, usize, usize) {
+        let order_costs = if periods_left == 0 {
+            &self.order_costs.1
+        } else {
+            &self.order_costs.0
+        };
+        // Cost saved by one more unit at a store, nothing once the store would leave the state space
+        let gain = |x: usize, q: usize, store: usize| match order_costs.get(&(x, q + 1, store)) {
+            Some(cost) => order_costs[&(x, q, store)] - cost,
+            None => 0.0,
+        };
+        let (mut q_a, mut q_b) = (0, 0);
+        while q_a + q_b < state.0 {
+            let gain_a = gain(state.1, q_a, 1);
+            let gain_b = gain(state.2, q_b, 2);
+            if gain_a <= 0.0 && gain_b <= 0.0 {
+                break;
+            }
+            if gain_a >= gain_b {
+                q_a += 1;
+            } else {
+                q_b += 1;
+            }
+        }
+
+        let order_up_to = match periods_left {
+            0 => 0,
+            1 => self.order_up_to.0,
+            _ => self.order_up_to.1,
+        };
+        let wh_order = order_up_to
+            .saturating_sub(state.0 + state.1 + state.2)
+            .min(policy_constructor.max_wh - 1 - (state.0 - q_a - q_b));
+        (wh_order, q_a, q_b)
+    }
+}
+
 pub fn calculate_lookahead(
     policy_constructor: &rust::policy_contructor::OptimalPolicy,
     expectation_all_one_step_lookahead_and_terminal: &(
@@ -12,6 +271,7 @@ pub fn calculate_lookahead(
     state: (usize, usize, usize),
     warehouse_order: usize,
     terminal_period: bool,
+    allocation: Option<&AllocationRule>,
 ) -> (usize, usize, usize, usize, usize) {
     let state_a = state.1;
     let state_b = state.2;
@@ -55,8 +315,9 @@ pub fn calculate_lookahead(
                 expecation_all_one_step_lookahead[&(wh, (state_b), 2)],
                 expecation_all_one_step_lookahead[&(wh, (state_b + 1), 2)],
             );
-            let alpha_a = f_vals_store_a.0 .0 - f_vals_store_a.1 .0;
-            let alpha_b = f_vals_store_b.0 .0 - f_vals_store_b.1 .0;
+            // Extra lost sales from giving up a unit, costed at each store's c_u_s
+            let alpha_a = policy_constructor.c_u_s.0 * (f_vals_store_a.0 .0 - f_vals_store_a.1 .0);
+            let alpha_b = policy_constructor.c_u_s.1 * (f_vals_store_b.0 .0 - f_vals_store_b.1 .0);
             // Return the smallest value as the relevant source store
             if alpha_a < alpha_b {
                 1
@@ -74,6 +335,10 @@ pub fn calculate_lookahead(
         );
 
         let mut transhipments_still_occur = true;
+        // Lost sales cost saved by the units moved so far, net of their per-unit transhipment cost
+        let mut net_saving = 0.0;
+        let source_start = source_info.1;
+        let destination_start = desintation_info.1;
 
         while transhipments_still_occur {
             // Check we are not at the state-space boundary
@@ -102,7 +367,12 @@ pub fn calculate_lookahead(
                         desintation_info.2,
                     )]
                         .0;
-                if delta - alpha > policy_constructor.c_ts / policy_constructor.c_u_s {
+                // Lost sales saved at the destination less those added at the source, each at its own store's c_u_s
+                let c_u_s_source = policy_constructor.store_params(source_info.2).2;
+                let c_u_s_destination = policy_constructor.store_params(desintation_info.2).2;
+                let saving = c_u_s_destination * delta - c_u_s_source * alpha;
+                let unit_cost = policy_constructor.unit_transhipment_cost(source_info.2);
+                if saving > unit_cost {
                     // check secondary condition
                     if (expecation_all_one_step_lookahead
                         [&(desintation_info.0, desintation_info.1, desintation_info.2)]
@@ -131,9 +401,15 @@ pub fn calculate_lookahead(
                     // Make transfer
                     source_info.1 -= 1;
                     desintation_info.1 += 1;
+                    net_saving += saving - unit_cost;
                 }
             }
         }
+        // Only ship if the units moved also cover the fixed cost of a shipment, otherwise order from the starting levels
+        if source_info.1 != source_start && net_saving <= policy_constructor.c_ts_fixed {
+            source_info.1 = source_start;
+            desintation_info.1 = destination_start;
+        }
         // Get ordering quantity based on the this state
         let q_source =
             expecation_all_one_step_lookahead[&(source_info.0, source_info.1, source_info.2)].2;
@@ -156,6 +432,14 @@ pub fn calculate_lookahead(
             )
         }
     };
+    if let Some(allocation) = allocation {
+        let post_transhipment = (
+            wh,
+            state_a - action.3 + action.4,
+            state_b - action.4 + action.3,
+        );
+        (action.1, action.2) = allocate_stock(post_transhipment, action.1, action.2, allocation);
+    }
     // Calculate warehouse order (uses regular base-stock policy)
     action.0 = max(
         warehouse_order as isize - max(wh as isize - (action.1 + action.2) as isize, 0) as isize,
@@ -172,6 +456,7 @@ pub fn calculate_lookahead_no_transhipment(
     state: (usize, usize, usize),
     warehouse_order: usize,
     terminal_period: bool,
+    allocation: Option<&AllocationRule>,
 ) -> (usize, usize, usize) {
     let state_a = state.1;
     let state_b = state.2;
@@ -187,8 +472,12 @@ pub fn calculate_lookahead_no_transhipment(
         
     // Save action to return
 
-    let q_a = expecation_all_one_step_lookahead[&(wh, state_a, 1)].2;
-    let q_b = expecation_all_one_step_lookahead[&(wh, state_b, 2)].2;
+    let mut q_a = expecation_all_one_step_lookahead[&(wh, state_a, 1)].2;
+    let mut q_b = expecation_all_one_step_lookahead[&(wh, state_b, 2)].2;
+    if let Some(allocation) = allocation {
+        let (alloc_a, alloc_b) = allocate_stock(state, q_a as usize, q_b as usize, allocation);
+        (q_a, q_b) = (alloc_a as f64, alloc_b as f64);
+    }
 
     // Calculate warehouse order (uses regular base-stock policy)
     let warehouse_q = max(
@@ -198,3 +487,47 @@ pub fn calculate_lookahead_no_transhipment(
 
     (warehouse_q, q_a as usize,q_b as usize)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::{ModelParams, OptimalPolicy};
+
+    #[test]
+    fn orders_stay_within_store_capacity() {
+        // A warehouse three times the size of a store, so an order of the whole warehouse overflows it
+        let model = ModelParams {
+            max_wh: Some(12),
+            ..small_model()
+        };
+        let policy_constructor = OptimalPolicy::new(&model, (0, 0));
+        let periods = 3;
+        let mut policies: Vec<Box<dyn Policy>> = vec![
+            Box::new(Lookahead::new(&policy_constructor, 10, (2.0, 1.5), None, true)),
+            Box::new(LookaheadNoTranshipment::new(&policy_constructor, 10, (2.0, 1.5), None, true)),
+            Box::new(Lookahead::new(
+                &policy_constructor,
+                10,
+                (2.0, 1.5),
+                Some(AllocationRule::LargestRequest),
+                false,
+            )),
+        ];
+        for policy in policies.iter_mut() {
+            policy.setup(periods);
+            for t in 1..=periods {
+                for state in policy_constructor.construct_state_space_iterator() {
+                    let action = policy.action(t, state);
+                    assert!(
+                        policy_constructor.is_feasible_action(state, action),
+                        "{:?} in period {} state {:?}",
+                        action,
+                        t,
+                        state
+                    );
+                }
+            }
+        }
+    }
+}
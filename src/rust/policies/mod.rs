@@ -1,4 +1,269 @@
 pub mod base_stock;
+pub mod callable;
 pub mod tie;
 pub mod esr;
-pub mod lookahead;
\ No newline at end of file
+pub mod pooling;
+pub mod hold_back;
+pub mod lookahead;
+pub mod k_step;
+pub mod rollout;
+
+use crate::rust;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::collections::HashMap;
+
+// State is (wh, sa, sb)
+pub type State = (usize, usize, usize);
+// Action is (wh_order, sa_order, sb_order, transhipments A->B, transhipments B->A)
+pub type Action = (usize, usize, usize, usize, usize);
+
+// Folds values into one seed with splitmix64, so seeded policies pick the same draws on every platform and Rust release
+pub fn mix_seed(values: &[u64]) -> u64 {
+    values.iter().fold(0x9E37_79B9_7F4A_7C15, |acc, value| {
+        let mut z = (acc ^ value).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    })
+}
+
+// A decision rule that can be evaluated by rust::evaluation::evaluate_policy.
+// setup is called once before the evaluation, so any precomputed tables belong there.
+// Randomised policies override action_distribution to give every action they may take with its probability,
+// the evaluation then takes the exact expectation over them.
+// Policies that also set the DFW threshold for the stock left after their action override dfw_threshold,
+// None keeps the model's fixed dfw_threshold.
+pub trait Policy: Send + Sync {
+    fn setup(&mut self, _periods: usize) {}
+    fn action(&self, t: usize, state: State) -> Action;
+    fn action_distribution(&self, t: usize, state: State) -> Vec<(Action, f64)> {
+        vec![(self.action(t, state), 1.0)]
+    }
+    fn dfw_threshold(&self, _t: usize, _state: State) -> Option<usize> {
+        None
+    }
+}
+
+// Transhipment half of a composite policy, returns (transhipments A->B, transhipments B->A)
+pub trait TranshipmentPolicy: Send + Sync {
+    fn setup(&mut self, _periods: usize) {}
+    fn transhipment(&self, t: usize, state: State) -> (usize, usize);
+    fn transhipment_distribution(&self, t: usize, state: State) -> Vec<((usize, usize), f64)> {
+        vec![(self.transhipment(t, state), 1.0)]
+    }
+}
+
+// Ordering half of a composite policy, sees the state after transhipments and returns (wh_order, sa_order, sb_order)
+pub trait OrderingPolicy: Send + Sync {
+    fn setup(&mut self, _periods: usize) {}
+    fn order(&self, t: usize, state: State) -> (usize, usize, usize);
+}
+
+// Tranship first, then order from the post-transhipment state
+pub struct Composite<'a> {
+    pub ordering: Box<dyn OrderingPolicy + 'a>,
+    pub transhipment: Box<dyn TranshipmentPolicy + 'a>,
+}
+
+impl Policy for Composite<'_> {
+    fn setup(&mut self, periods: usize) {
+        self.transhipment.setup(periods);
+        self.ordering.setup(periods);
+    }
+
+    fn action(&self, t: usize, state: State) -> Action {
+        let transhipment_action = self.transhipment.transhipment(t, state);
+        self.order_after(t, state, transhipment_action)
+    }
+
+    fn action_distribution(&self, t: usize, state: State) -> Vec<(Action, f64)> {
+        self.transhipment
+            .transhipment_distribution(t, state)
+            .into_iter()
+            .map(|(transhipment_action, prob)| {
+                (self.order_after(t, state, transhipment_action), prob)
+            })
+            .collect()
+    }
+}
+
+impl Composite<'_> {
+    fn order_after(&self, t: usize, state: State, transhipment_action: (usize, usize)) -> Action {
+        let ordering_action = self.ordering.order(
+            t,
+            (
+                state.0,
+                state.1 - transhipment_action.0 + transhipment_action.1,
+                state.2 - transhipment_action.1 + transhipment_action.0,
+            ),
+        );
+        (
+            ordering_action.0,
+            ordering_action.1,
+            ordering_action.2,
+            transhipment_action.0,
+            transhipment_action.1,
+        )
+    }
+}
+
+pub struct NoTranshipment;
+
+impl TranshipmentPolicy for NoTranshipment {
+    fn transhipment(&self, _t: usize, _state: State) -> (usize, usize) {
+        (0, 0)
+    }
+}
+
+// Builds a heuristic from the policy codes used by policy_evaluation_par_bs.
+// Transhipment policy can be 'N' - No transhipment, 'T' - TIE, 'E' - ESR or 'L' - Lookahead Policy, 'O' - one step ahead no transhipment,
+// 'K' - k step lookahead over lookahead_steps periods, 'R' - rollout on the base heuristic in rollout,
+// 'P' - complete pooling or 'H' - hold-back with the levels in hold_back
+// Ordering policy can be 'R' for regular base-stock or 'C' for regular with order caps ('L', 'O' and 'K' choose their own orders)
+// TIE is randomised, tie_seed swaps the exact expectation over its coin flip for a seeded deterministic tie-break
+// Allocation rule splits a warehouse short of the store orders, see base_stock::AllocationRule::from_code.
+// Base-stock ordering defaults to 'R', the lookahead policies keep their mean demand split of the warehouse unless one is given
+// optimise_warehouse has 'L' and 'O' allocate by marginal value and pick the warehouse order themselves, see lookahead::MarginalOrdering
+// terminal_cost is the cost of the stock left at the end of the horizon, None for zero terminal cost. 'K' and 'R' look ahead to it
+pub fn heuristic_policy<'a>(
+    policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+    transhipment_policy: char,
+    ordering_policy: char,
+    base_stock_policy: (usize, usize, usize),
+    order_cap: Option<(usize, usize)>,
+    store_means: (f64, f64),
+    tie_seed: Option<u64>,
+    allocation_rule: Option<char>,
+    optimise_warehouse: bool,
+    hold_back: Option<hold_back::HoldBackLevels>,
+    lookahead_steps: usize,
+    rollout: &rollout::RolloutOptions,
+    terminal_cost: Option<&HashMap<State, f64>>,
+) -> PyResult<Box<dyn Policy + 'a>> {
+    if transhipment_policy == 'R' {
+        if rollout.base == 'R' {
+            return Err(PyValueError::new_err("Rollout can't be its own base heuristic"));
+        }
+        let base = heuristic_policy(
+            policy_constructor,
+            rollout.base,
+            ordering_policy,
+            base_stock_policy,
+            order_cap,
+            store_means,
+            tie_seed,
+            allocation_rule,
+            optimise_warehouse,
+            hold_back,
+            lookahead_steps,
+            rollout,
+            terminal_cost,
+        )?;
+        return Ok(Box::new(rollout::Rollout::new(
+            policy_constructor,
+            base,
+            rollout.simulations,
+            rollout.seed,
+            terminal_cost.cloned(),
+        )));
+    }
+    let allocation = allocation_rule
+        .map(|code| base_stock::AllocationRule::from_code(code, store_means))
+        .transpose()?;
+    // Integrated policies pick orders and transhipments together
+    if transhipment_policy == 'L' {
+        return Ok(Box::new(lookahead::Lookahead::new(
+            policy_constructor,
+            base_stock_policy.0,
+            store_means,
+            allocation,
+            optimise_warehouse,
+        )));
+    } else if transhipment_policy == 'O' {
+        return Ok(Box::new(lookahead::LookaheadNoTranshipment::new(
+            policy_constructor,
+            base_stock_policy.0,
+            store_means,
+            allocation,
+            optimise_warehouse,
+        )));
+    }
+    let allocation = allocation.unwrap_or(base_stock::AllocationRule::LargestRequest);
+    if transhipment_policy == 'K' {
+        let order_cap = match ordering_policy {
+            'R' => None,
+            'C' => order_cap,
+            _ => return Err(PyValueError::new_err("Ordering policy not recognised")),
+        };
+        return Ok(Box::new(k_step::KStepLookahead::new(
+            policy_constructor,
+            lookahead_steps,
+            base_stock_policy,
+            order_cap,
+            allocation,
+            terminal_cost.cloned(),
+        )));
+    }
+
+    let transhipment: Box<dyn TranshipmentPolicy + 'a> = if transhipment_policy == 'N' {
+        Box::new(NoTranshipment)
+    } else if transhipment_policy == 'T' {
+        Box::new(tie::Tie::new(policy_constructor, store_means, tie_seed))
+    } else if transhipment_policy == 'E' {
+        Box::new(esr::Esr::new(
+            policy_constructor,
+            (base_stock_policy.1, base_stock_policy.2),
+        ))
+    } else if transhipment_policy == 'P' {
+        Box::new(pooling::CompletePooling::new(policy_constructor))
+    } else if transhipment_policy == 'H' {
+        let Some(levels) = hold_back else {
+            return Err(PyValueError::new_err(
+                "Hold-back policy needs hold_back and hold_back_trigger",
+            ));
+        };
+        Box::new(hold_back::HoldBack::new(policy_constructor, levels))
+    } else {
+        return Err(PyValueError::new_err("Transhipment policy not recognised"));
+    };
+
+    let ordering: Box<dyn OrderingPolicy + 'a> = if ordering_policy == 'R' {
+        Box::new(base_stock::RegularBaseStock::new(
+            base_stock_policy,
+            None,
+            allocation,
+        ))
+    } else if ordering_policy == 'C' {
+        Box::new(base_stock::RegularBaseStock::new(
+            base_stock_policy,
+            order_cap,
+            allocation,
+        ))
+    } else {
+        return Err(PyValueError::new_err("Ordering policy not recognised"));
+    };
+
+    Ok(Box::new(Composite {
+        ordering,
+        transhipment,
+    }))
+}
+
+// Fixed table of actions keyed by (t, wh, sa, sb), e.g. the policy returned by optimal_policy_par,
+// optionally with the DFW thresholds it chose with optimise_dfw_threshold
+pub struct TablePolicy<'a> {
+    pub actions: &'a rust::arrays::PolicyTable,
+    pub dfw_thresholds: Option<&'a rust::arrays::DfwThresholdTable>,
+}
+
+impl Policy for TablePolicy<'_> {
+    fn action(&self, t: usize, state: State) -> Action {
+        self.actions[&(t, state.0, state.1, state.2)]
+    }
+
+    fn dfw_threshold(&self, t: usize, state: State) -> Option<usize> {
+        self.dfw_thresholds
+            .map(|dfw_thresholds| dfw_thresholds[&(t, state.0, state.1, state.2)])
+    }
+}
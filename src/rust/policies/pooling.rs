@@ -0,0 +1,94 @@
+use crate::rust;
+use crate::rust::policies::{State, TranshipmentPolicy};
+
+// Complete pooling, redistributes the stock of both stores so their projected stockout risk, the chance one period of
+// demand exceeds the store's stock before its next order arrives, is as equal as the state space allows.
+// Among equally balanced splits the one moving the fewest units is taken. Transhipment costs do not enter the rule.
+pub struct CompletePooling<'a> {
+    policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+    // Probability that demand exceeds each stock level of (store A, store B)
+    stockout_risk: (Vec<f64>, Vec<f64>),
+}
+
+impl<'a> CompletePooling<'a> {
+    pub fn new(policy_constructor: &'a rust::policy_contructor::OptimalPolicy) -> Self {
+        CompletePooling {
+            policy_constructor,
+            stockout_risk: (Vec::new(), Vec::new()),
+        }
+    }
+}
+
+impl TranshipmentPolicy for CompletePooling<'_> {
+    fn setup(&mut self, _periods: usize) {
+        let policy_constructor = self.policy_constructor;
+        let stockout_risk = |store: usize, max_s: usize| -> Vec<f64> {
+            let d_pmf = policy_constructor.store_params(store).0;
+            (0..max_s)
+                .map(|level| d_pmf.iter().skip(level + 1).sum())
+                .collect()
+        };
+        self.stockout_risk = (
+            stockout_risk(1, policy_constructor.max_sa),
+            stockout_risk(2, policy_constructor.max_sb),
+        );
+    }
+
+    fn transhipment(&self, _t: usize, state: State) -> (usize, usize) {
+        let total = state.1 + state.2;
+        // Levels of store A that leave store B inside the state space
+        let lowest_a = total.saturating_sub(self.policy_constructor.max_sb - 1);
+        let highest_a = total.min(self.policy_constructor.max_sa - 1);
+
+        let mut best_a = state.1;
+        let mut best_gap = f64::INFINITY;
+        for level_a in lowest_a..=highest_a {
+            let gap = (self.stockout_risk.0[level_a] - self.stockout_risk.1[total - level_a]).abs();
+            let fewer_moved = level_a.abs_diff(state.1) < best_a.abs_diff(state.1);
+            if gap < best_gap || (gap == best_gap && fewer_moved) {
+                best_a = level_a;
+                best_gap = gap;
+            }
+        }
+        if best_a < state.1 {
+            (state.1 - best_a, 0)
+        } else {
+            (0, best_a - state.1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::{ModelParams, OptimalPolicy};
+
+    #[test]
+    fn identical_stores_are_split_evenly() {
+        let policy_constructor = OptimalPolicy::new(&ModelParams { sb_demand_param_one: 2.0, ..small_model() }, (0, 0));
+        let mut pooling = CompletePooling::new(&policy_constructor);
+        pooling.setup(1);
+        assert_eq!(pooling.transhipment(1, (0, 3, 1)), (1, 0));
+        assert_eq!(pooling.transhipment(1, (0, 0, 2)), (0, 1));
+        assert_eq!(pooling.transhipment(1, (0, 2, 2)), (0, 0));
+        // An odd unit out stays where it is rather than moving one more
+        assert_eq!(pooling.transhipment(1, (0, 3, 0)), (1, 0));
+        assert_eq!(pooling.transhipment(1, (0, 0, 3)), (0, 1));
+    }
+
+    #[test]
+    fn busier_store_gets_more_stock() {
+        // Store A sells 2.0 a period on average and store B 1.5
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let mut pooling = CompletePooling::new(&policy_constructor);
+        pooling.setup(1);
+        for state in policy_constructor.construct_state_space_iterator() {
+            let (t_a_to_b, t_b_to_a) = pooling.transhipment(1, state);
+            assert!(policy_constructor.is_feasible_action(state, (0, 0, 0, t_a_to_b, t_b_to_a)));
+            let after = (state.1 - t_a_to_b + t_b_to_a, state.2 - t_b_to_a + t_a_to_b);
+            let room_a = policy_constructor.max_sa - 1;
+            assert!(after.0 >= after.1 || after.0 == room_a, "state {:?} pooled to {:?}", state, after);
+        }
+    }
+}
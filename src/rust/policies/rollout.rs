@@ -0,0 +1,240 @@
+use crate::rust;
+use crate::rust::policies::{Action, Policy, State};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+// Base heuristic of a rollout, a transhipment policy code of heuristic_policy, and how its cost is found.
+// Without simulations the base heuristic is evaluated exactly, otherwise by that many simulated runs from seed.
+pub struct RolloutOptions {
+    pub base: char,
+    pub simulations: Option<usize>,
+    pub seed: u64,
+}
+
+// Rollout on a base heuristic. Every action of generate_action_space is scored by its immediate cost plus the discounted
+// cost of following the base heuristic from the next state to the end of the horizon, and the cheapest is taken.
+// Simulated runs use common random numbers. The draws of every period of a run are seeded by (seed, t, state, run, period)
+// whatever the action, with the base heuristic's randomised actions drawn from a stream of their own. A period's draws
+// start with the online and store demand, so run i of every action sees the same demand in every period however many
+// draws the earlier periods took, and the actions are compared like for like.
+// The base heuristic's cost includes the terminal cost of the stock left at the end of the horizon.
+pub struct Rollout<'a> {
+    policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+    base: Box<dyn Policy + 'a>,
+    simulations: Option<usize>,
+    seed: u64,
+    terminal_cost: Option<HashMap<State, f64>>,
+    periods: usize,
+    store_expectation: HashMap<State, f64>,
+    warehouse_expectation: HashMap<State, f64>,
+    // Exact value function of the base heuristic for periods 2..=periods + 1, keyed by period
+    base_values: HashMap<usize, HashMap<State, f64>>,
+}
+
+impl<'a> Rollout<'a> {
+    pub fn new(
+        policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+        base: Box<dyn Policy + 'a>,
+        simulations: Option<usize>,
+        seed: u64,
+        terminal_cost: Option<HashMap<State, f64>>,
+    ) -> Self {
+        Rollout {
+            policy_constructor,
+            base,
+            simulations,
+            seed,
+            terminal_cost,
+            periods: 0,
+            store_expectation: HashMap::new(),
+            warehouse_expectation: HashMap::new(),
+            base_values: HashMap::new(),
+        }
+    }
+
+    // Draws of one stage of period `period` in run `run` of the rollout from (t, state)
+    fn run_rng(&self, t: usize, state: State, run: usize, period: usize, stage: Stage) -> StdRng {
+        StdRng::seed_from_u64(rust::policies::mix_seed(&[
+            self.seed,
+            t as u64,
+            state.0 as u64,
+            state.1 as u64,
+            state.2 as u64,
+            run as u64,
+            period as u64,
+            stage as u64,
+        ]))
+    }
+
+    // Discounted cost of run `run` of the base heuristic from the state reached at the start of period t + 1
+    // by an action in state at period t
+    fn simulate_base(&self, t: usize, state: State, run: usize, next_state: State) -> f64 {
+        let policy_constructor = self.policy_constructor;
+        let mut state_u = next_state;
+        let mut cost = 0.0;
+        let mut discount = 1.0;
+        for u in t + 1..=self.periods {
+            let actions = self.base.action_distribution(u, state_u);
+            let mut draw = self.run_rng(t, state, run, u, Stage::Action).random::<f64>();
+            let mut action = actions[actions.len() - 1].0;
+            for (candidate, prob) in &actions {
+                if draw < *prob {
+                    action = *candidate;
+                    break;
+                }
+                draw -= prob;
+            }
+            cost += discount
+                * rust::value_function::immediate_cost(
+                    policy_constructor,
+                    state_u,
+                    action,
+                    &self.store_expectation,
+                    &self.warehouse_expectation,
+                );
+            state_u = rust::value_function::sample_transition(
+                policy_constructor,
+                post_action_state(state_u, action),
+                (action.0, action.1, action.2),
+                &mut self.run_rng(t, state, run, u, Stage::Transition),
+            );
+            discount *= policy_constructor.gamma;
+        }
+        cost + discount * self.terminal(state_u)
+    }
+
+    fn terminal(&self, state: State) -> f64 {
+        self.terminal_cost.as_ref().map_or(0.0, |terminal_cost| terminal_cost[&state])
+    }
+}
+
+impl Policy for Rollout<'_> {
+    fn setup(&mut self, periods: usize) {
+        let policy_constructor = self.policy_constructor;
+        self.base.setup(periods);
+        self.periods = periods;
+        self.store_expectation = policy_constructor.expectation_all_stores();
+        self.warehouse_expectation = policy_constructor.expectation_all_warehouse();
+        self.base_values.clear();
+        if self.simulations.is_some() {
+            return;
+        }
+
+        // Backward induction for the base heuristic, as in evaluate_policy
+        let state_space: Vec<State> = policy_constructor.construct_state_space_iterator().collect();
+        let mut v: HashMap<State, f64> = state_space.iter().map(|state| (*state, self.terminal(*state))).collect();
+        for t in (2..=periods).rev() {
+            self.base_values.insert(t + 1, v.clone());
+            let this = &*self;
+            v = state_space
+                .par_iter()
+                .map(|state| {
+                    let v_t_x: f64 = this
+                        .base
+                        .action_distribution(t, *state)
+                        .iter()
+                        .map(|(action, prob)| {
+                            prob * rust::value_function::value_function_pol_eval(
+                                policy_constructor,
+                                *state,
+                                &v,
+                                *action,
+                                &this.store_expectation,
+                                &this.warehouse_expectation,
+                            )
+                        })
+                        .sum();
+                    (*state, v_t_x)
+                })
+                .collect();
+        }
+        self.base_values.insert(2, v);
+    }
+
+    fn action(&self, t: usize, state: State) -> Action {
+        let policy_constructor = self.policy_constructor;
+
+        let mut best_action: Option<(Action, f64)> = None;
+        for action in policy_constructor.generate_action_space(state) {
+            let cost = match self.simulations {
+                None => rust::value_function::value_function_pol_eval(
+                    policy_constructor,
+                    state,
+                    &self.base_values[&(t + 1)],
+                    action,
+                    &self.store_expectation,
+                    &self.warehouse_expectation,
+                ),
+                Some(simulations) => {
+                    let mut future = 0.0;
+                    for run in 0..simulations {
+                        let next_state = rust::value_function::sample_transition(
+                            policy_constructor,
+                            post_action_state(state, action),
+                            (action.0, action.1, action.2),
+                            &mut self.run_rng(t, state, run, t, Stage::Transition),
+                        );
+                        future += self.simulate_base(t, state, run, next_state);
+                    }
+                    rust::value_function::immediate_cost(
+                        policy_constructor,
+                        state,
+                        action,
+                        &self.store_expectation,
+                        &self.warehouse_expectation,
+                    ) + policy_constructor.gamma * future / simulations.max(1) as f64
+                }
+            };
+            if best_action.is_none() || cost < best_action.unwrap().1 {
+                best_action = Some((action, cost));
+            }
+        }
+        best_action.unwrap().0
+    }
+}
+
+// Separate streams for the transition of a simulated period and for the base heuristic's randomised action in it
+#[derive(Clone, Copy)]
+enum Stage {
+    Transition = 0,
+    Action = 1,
+}
+
+fn post_action_state(state: State, action: Action) -> State {
+    let (_, st_a_order, st_b_order, t_a_to_b, t_b_to_a) = action;
+    (
+        state.0 - st_a_order - st_b_order,
+        state.1 - t_a_to_b + t_b_to_a,
+        state.2 - t_b_to_a + t_a_to_b,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policies::base_stock::{AllocationRule, RegularBaseStock};
+    use crate::rust::policies::{Composite, NoTranshipment};
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::OptimalPolicy;
+
+    #[test]
+    fn simulated_rollout_matches_exact_rollout() {
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let rollout = |simulations: Option<usize>| {
+            let base = Composite {
+                ordering: Box::new(RegularBaseStock::new((5, 3, 3), None, AllocationRule::LargestRequest)),
+                transhipment: Box::new(NoTranshipment),
+            };
+            let mut rollout = Rollout::new(&policy_constructor, Box::new(base), simulations, 3, None);
+            rollout.setup(2);
+            rollout
+        };
+        let exact = rollout(None);
+        let simulated = rollout(Some(1000));
+        for state in [(0, 0, 0), (5, 3, 0), (2, 0, 3), (4, 1, 2), (1, 3, 3)] {
+            assert_eq!(exact.action(1, state), simulated.action(1, state), "state {:?}", state);
+        }
+    }
+}
@@ -1,20 +1,135 @@
-use rand::prelude::*;
+use crate::rust;
+use crate::rust::policies::{State, TranshipmentPolicy};
 use std::cmp::max;
+use std::collections::HashMap;
 
-pub fn calculate_tie(
+// Transhipment Inventory Equalisation, rebalances store stock in proportion to mean demand.
+// When the balanced split is fractional the spare unit goes to either store with probability 1/2,
+// evaluated exactly unless a tie-break seed is given, in which case one store is picked deterministically per (t, state).
+// With a fixed transhipment cost a rebalance only goes ahead when the lost sales it saves, by the same
+// one step ahead expectations as ESR, cover its per-unit and fixed cost.
+pub struct Tie<'a> {
+    policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+    store_means: (f64, f64),
+    max_sa: usize,
+    max_sb: usize,
+    tie_break_seed: Option<u64>,
+    one_step_ahead_expectations: rust::policies::esr::OneStepAheadExpectations,
+    final_period: usize,
+}
+
+impl<'a> Tie<'a> {
+    pub fn new(
+        policy_constructor: &'a rust::policy_contructor::OptimalPolicy,
+        store_means: (f64, f64),
+        tie_break_seed: Option<u64>,
+    ) -> Self {
+        Tie {
+            policy_constructor,
+            store_means,
+            max_sa: policy_constructor.max_sa,
+            max_sb: policy_constructor.max_sb,
+            tie_break_seed,
+            one_step_ahead_expectations: (HashMap::new(), HashMap::new()),
+            final_period: 0,
+        }
+    }
+
+    // Whether the lost sales saved by a transhipment cover its cost, always true without a fixed cost
+    fn justified(&self, t: usize, state: State, transhipment: (usize, usize)) -> bool {
+        let (t_a_to_b, t_b_to_a) = transhipment;
+        if self.policy_constructor.c_ts_fixed <= 0.0 || t_a_to_b + t_b_to_a == 0 {
+            return true;
+        }
+        let expectations = if t == self.final_period {
+            &self.one_step_ahead_expectations.1
+        } else {
+            &self.one_step_ahead_expectations.0
+        };
+        let base_stock = (
+            self.policy_constructor.base_stock_a,
+            self.policy_constructor.base_stock_b,
+        );
+        // Expected lost sales of a store at a level
+        let lost_sales = |level: usize, store: usize| {
+            let bs = if store == 1 { base_stock.0 } else { base_stock.1 };
+            expectations[&(level, store, bs)].0
+        };
+        let (source, source_level, destination, destination_level, units) = if t_a_to_b > 0 {
+            (1, state.1, 2, state.2, t_a_to_b)
+        } else {
+            (2, state.2, 1, state.1, t_b_to_a)
+        };
+        let saving = self.policy_constructor.store_params(destination).2
+            * (lost_sales(destination_level, destination)
+                - lost_sales(destination_level + units, destination))
+            - self.policy_constructor.store_params(source).2
+                * (lost_sales(source_level - units, source) - lost_sales(source_level, source));
+        saving > self.policy_constructor.transhipment_cost(t_a_to_b, t_b_to_a)
+    }
+
+    fn outcomes(&self, state: State) -> Vec<((usize, usize), f64)> {
+        tie_outcomes(
+            state.1,
+            state.2,
+            self.store_means.0,
+            self.store_means.1,
+            self.max_sa,
+            self.max_sb,
+        )
+    }
+}
+
+impl TranshipmentPolicy for Tie<'_> {
+    fn setup(&mut self, periods: usize) {
+        if self.policy_constructor.c_ts_fixed > 0.0 {
+            self.one_step_ahead_expectations = self.policy_constructor.all_one_step_ahead_out();
+        }
+        self.final_period = periods;
+    }
+
+    fn transhipment(&self, t: usize, state: State) -> (usize, usize) {
+        self.transhipment_distribution(t, state)[0].0
+    }
+
+    fn transhipment_distribution(&self, t: usize, state: State) -> Vec<((usize, usize), f64)> {
+        let outcomes: Vec<((usize, usize), f64)> = self
+            .outcomes(state)
+            .into_iter()
+            .map(|(transhipment, prob)| {
+                if self.justified(t, state, transhipment) {
+                    (transhipment, prob)
+                } else {
+                    ((0, 0), prob)
+                }
+            })
+            .collect();
+        match self.tie_break_seed {
+            Some(seed) if outcomes.len() > 1 => {
+                let add_to_a = rust::policies::mix_seed(&[seed, t as u64, state.0 as u64, state.1 as u64, state.2 as u64])
+                    .is_multiple_of(2);
+                vec![(if add_to_a { outcomes[0].0 } else { outcomes[1].0 }, 1.0)]
+            }
+            _ => outcomes,
+        }
+    }
+}
+
+// Every TIE transhipment with its probability. With a fractional split the first outcome adds the spare unit to store a
+pub fn tie_outcomes(
     state_store_a: usize,
     state_store_b: usize,
     demand_store_a: f64,
     demand_store_b: f64,
     max_sa : usize,
     max_sb : usize,
-) -> (usize, usize) {
+) -> Vec<((usize, usize), f64)> {
 
     let max_sa = (max_sa-1) as f64;
     let max_sb = (max_sb-1) as f64;
-    let mut rebalanced_store_a = f64::min((demand_store_a as f64 / (demand_store_a + demand_store_b))
+    let rebalanced_store_a = f64::min((demand_store_a as f64 / (demand_store_a + demand_store_b))
         * (state_store_a + state_store_b) as f64,max_sa as f64) as f64;
-    let mut rebalanced_store_b = f64::min((demand_store_b as f64 / (demand_store_a + demand_store_b))
+    let rebalanced_store_b = f64::min((demand_store_b as f64 / (demand_store_a + demand_store_b))
         * (state_store_a + state_store_b) as f64, max_sb as f64) as f64;
 
     // Check if integer
@@ -23,7 +138,7 @@ pub fn calculate_tie(
             max(rebalanced_store_a as isize - state_store_a as isize, 0) as usize;
         let store_a_b_transhipment =
             max(rebalanced_store_b as isize - state_store_b as isize, 0) as usize;
-        return (store_a_b_transhipment, store_b_a_transhipment as usize);
+        return vec![((store_a_b_transhipment, store_b_a_transhipment as usize), 1.0)];
     } else {
         // Just round rather than randomly allocate here (for two stores its equivalent)
         let rebalanced_store_a_min = rebalanced_store_a.floor();
@@ -34,34 +149,123 @@ pub fn calculate_tie(
         if excess > 1 {
             panic!("Excess greater than 1");
         }
-        // Randomly allocate excess
-        let mut rng = rand::rng();
-         // If we're already at the limit of the state space then just add to the other store
-        let add_to_a = if rebalanced_store_a_min == max_sa as f64 {
-            false
+
+        // Transhipment when the excess goes to store a (add_to_a) or store b
+        let transhipment = |add_to_a: bool| {
+            let rebalanced_store_a = if add_to_a {
+                rebalanced_store_a_min + 1.0
+            } else {
+                rebalanced_store_a_min
+            };
+            let rebalanced_store_b = if add_to_a {
+                rebalanced_store_b_min
+            } else {
+                rebalanced_store_b_min + 1.0
+            };
+            let store_b_a_transhipment =
+                max(rebalanced_store_a as isize - state_store_a as isize, 0) as usize;
+            let store_a_b_transhipment =
+                max(rebalanced_store_b as isize - state_store_b as isize, 0) as usize;
+            (store_a_b_transhipment, store_b_a_transhipment)
+        };
+
+        // If we're already at the limit of the state space then just add to the other store
+        if rebalanced_store_a_min == max_sa as f64 {
+            vec![(transhipment(false), 1.0)]
         } else if rebalanced_store_b_min == max_sb as f64 {
-            true
+            vec![(transhipment(true), 1.0)]
         } else {
-            rng.random_bool(0.5)
-        }; // If to add the excess demand to store a
+            vec![(transhipment(true), 0.5), (transhipment(false), 0.5)]
+        }
+    }
+}
 
-       
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policies::base_stock::{AllocationRule, RegularBaseStock};
+    use crate::rust::policies::{Composite, Policy, TablePolicy};
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::OptimalPolicy;
+    use dashmap::DashMap;
 
-        rebalanced_store_a = if add_to_a {
-            rebalanced_store_a_min + 1.0
-        } else {
-            rebalanced_store_a_min
+    // Value function of the policy from zero terminal cost, and the action it takes in every period and state
+    fn evaluate(
+        policy_constructor: &rust::policy_contructor::OptimalPolicy,
+        policy: &mut dyn Policy,
+        periods: usize,
+    ) -> (HashMap<State, f64>, rust::arrays::PolicyTable) {
+        pyo3::prepare_freethreaded_python();
+        policy.setup(periods);
+        let v = DashMap::new();
+        for state in policy_constructor.construct_state_space_iterator() {
+            v.insert(state, 0.0);
+        }
+        let progress = rust::progress::ProgressTracker::new(None, 0, 0);
+        let actions = rust::evaluation::evaluate_policy(policy_constructor, policy, periods, &v, None, &progress).unwrap();
+        (v.into_iter().collect(), actions.into_iter().collect())
+    }
+
+    #[test]
+    fn exact_value_is_the_mean_of_the_two_splits() {
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let tie_policy = |seed: Option<u64>| Composite {
+            ordering: Box::new(RegularBaseStock::new((5, 3, 3), None, AllocationRule::LargestRequest)),
+            transhipment: Box::new(Tie::new(&policy_constructor, (2.0, 1.5), seed)),
         };
-        rebalanced_store_b =  if add_to_a {
-            rebalanced_store_b_min
-        } else {
-            rebalanced_store_b_min + 1.0
+        // One period, so the value of each state is the cost of that period's action alone
+        let periods = 1;
+        let mut exact = tie_policy(None);
+        let (v_exact, _) = evaluate(&policy_constructor, &mut exact, periods);
+
+        // Deterministic policies that always give the spare unit to store A (ceil split) or to store B (floor split)
+        let split_table = |split: usize| -> rust::arrays::PolicyTable {
+            policy_constructor
+                .construct_state_space_iterator()
+                .map(|state| {
+                    let actions = exact.action_distribution(1, state);
+                    ((1, state.0, state.1, state.2), actions[split.min(actions.len() - 1)].0)
+                })
+                .collect()
         };
-        let store_b_a_transhipment =
-            max(rebalanced_store_a as isize - state_store_a as isize, 0) as usize;
-        let store_a_b_transhipment =
-            max(rebalanced_store_b as isize - state_store_b as isize, 0) as usize;
+        let (ceil_table, floor_table) = (split_table(0), split_table(1));
+        let (v_ceil, _) = evaluate(
+            &policy_constructor,
+            &mut TablePolicy { actions: &ceil_table, dfw_thresholds: None },
+            periods,
+        );
+        let (v_floor, _) = evaluate(
+            &policy_constructor,
+            &mut TablePolicy { actions: &floor_table, dfw_thresholds: None },
+            periods,
+        );
 
-        return (store_a_b_transhipment, store_b_a_transhipment);
+        let mut split_states = 0;
+        for state in policy_constructor.construct_state_space_iterator() {
+            let mean = (v_ceil[&state] + v_floor[&state]) / 2.0;
+            assert!((v_exact[&state] - mean).abs() < 1e-9, "state {:?}", state);
+            if ceil_table[&(1, state.0, state.1, state.2)] != floor_table[&(1, state.0, state.1, state.2)] {
+                split_states += 1;
+                assert!((v_ceil[&state] - v_floor[&state]).abs() > 1e-9, "state {:?}", state);
+            }
+        }
+        assert!(split_states > 0);
+    }
+
+    #[test]
+    fn seeded_tie_break_is_reproducible() {
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let tie_policy = |seed: Option<u64>| Composite {
+            ordering: Box::new(RegularBaseStock::new((5, 3, 3), None, AllocationRule::LargestRequest)),
+            transhipment: Box::new(Tie::new(&policy_constructor, (2.0, 1.5), seed)),
+        };
+        let periods = 2;
+        let (v_first, actions_first) = evaluate(&policy_constructor, &mut tie_policy(Some(7)), periods);
+        let (v_second, actions_second) = evaluate(&policy_constructor, &mut tie_policy(Some(7)), periods);
+        assert_eq!(actions_first, actions_second);
+        assert_eq!(v_first, v_second);
+        // Another seed breaks at least one tie the other way
+        let (_, actions_other) = evaluate(&policy_constructor, &mut tie_policy(Some(8)), periods);
+        assert_ne!(actions_first, actions_other);
     }
 }
@@ -7,7 +7,9 @@
 use crate::rust;
 //use itertools::Itertools;
 use itertools::iproduct;
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
+use rand::{Rng, RngCore};
 use statrs::distribution::{Binomial, Discrete};
 use std::cmp::max;
 use std::cmp::min;
@@ -15,15 +17,94 @@ use std::collections::HashMap;
 use std::usize;
 use std::vec;
 
+// Transhipment parameter given from Python either once for both directions or as (A->B, B->A)
+#[derive(Clone, Copy)]
+pub struct PerDirection(pub f64, pub f64);
+
+impl<'py> FromPyObject<'py> for PerDirection {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        let PerStore(a_to_b, b_to_a) = ob.extract()?;
+        Ok(PerDirection(a_to_b, b_to_a))
+    }
+}
+
+// Probability table of DFW acceptances, indexed [customers short][customers accepting]
+pub type BinomTable = [[f64; crate::D_MAX + 1]; crate::D_MAX + 1];
+
+// One outcome of OptimalPolicy::for_each_demand_outcome_rationed as (demand, dfw, online, emergency)
+pub type DemandOutcome = ((usize, usize), (usize, usize), (usize, usize), (usize, usize));
+
+// Random source of the stages of a period, None enumerates every outcome and Some draws one
+type Sampler<'r> = Option<&'r mut dyn RngCore>;
+
+// Store-level parameter given from Python either once for both stores or as (store A, store B)
+#[derive(Clone, Copy)]
+pub struct PerStore(pub f64, pub f64);
+
+impl<'py> FromPyObject<'py> for PerStore {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if let Ok((a, b)) = ob.extract::<(f64, f64)>() {
+            return Ok(PerStore(a, b));
+        }
+        let value: f64 = ob.extract()?;
+        Ok(PerStore(value, value))
+    }
+}
+
+// Order in which the warehouse serves the two stores' DFW customers when it cannot serve them all.
+// Given from Python as 'A' - store A first, 'B' - store B first, 'R' - a fair coin picks the store served first,
+// 'S' - shared in proportion to the customers accepting DFW at each store
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DfwOrder {
+    AFirst,
+    BFirst,
+    Random,
+    Proportional,
+}
+
+impl<'py> FromPyObject<'py> for DfwOrder {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        match ob.extract::<char>()? {
+            'A' => Ok(DfwOrder::AFirst),
+            'B' => Ok(DfwOrder::BFirst),
+            'R' => Ok(DfwOrder::Random),
+            'S' => Ok(DfwOrder::Proportional),
+            _ => Err(PyValueError::new_err("DFW order not recognised")),
+        }
+    }
+}
+
 pub struct OptimalPolicy {
-    pub h_s: f64,
+    // Store-level costs are (store A, store B)
+    pub h_s: (f64, f64),
     pub h_w: f64,
-    pub c_u_s: f64,
-    pub c_p: f64,
-    pub c_ts: f64,
+    pub c_u_s: (f64, f64),
+    pub c_p: (f64, f64),
+    // Per-unit transhipment cost (A->B, B->A) and the fixed cost of any shipment
+    pub c_ts: (f64, f64),
+    pub c_ts_fixed: f64,
     pub da_pmf: [f64; crate::D_MAX],
     pub db_pmf: [f64; crate::D_MAX],
-    pub binom_pmf: [[f64; crate::D_MAX + 1]; crate::D_MAX + 1],
+    // Online orders shipped straight from the warehouse, with the lost sales cost of each one it cannot fill.
+    // With no online demand the pmf puts all its mass on 0
+    pub dw_pmf: [f64; crate::D_MAX],
+    pub c_u_w: f64,
+    // binom_pmf_a[n][j] is the probability j of n customers short at store A accept DFW, likewise for store B
+    pub binom_pmf_a: BinomTable,
+    pub binom_pmf_b: BinomTable,
+    // Probability an unmet customer at (store A, store B) walks to the other store, and the tables of how many walk
+    pub substitution: (f64, f64),
+    pub substitution_pmf_a: BinomTable,
+    pub substitution_pmf_b: BinomTable,
+    // How many unmet customers the one step ahead heuristics see recovered, by walking or accepting DFW
+    pub recovery_pmf_a: BinomTable,
+    pub recovery_pmf_b: BinomTable,
+    pub dfw_order: DfwOrder,
+    // Per-unit cost (A->B, B->A) of an emergency transhipment after demand, sending one store's leftover stock to the
+    // customers waiting at the other. Without it shortages are only offered DFW
+    pub c_ts_emergency: Option<(f64, f64)>,
+    // DFW only uses warehouse stock above this level, the rest is kept for store replenishment
+    pub dfw_threshold: usize,
     pub max_wh: usize,
     pub max_sa: usize,
     pub max_sb: usize,
@@ -32,18 +113,47 @@ pub struct OptimalPolicy {
     pub base_stock_b: usize,
 }
 
-impl OptimalPolicy {
+// Model parameters every entry point takes from Python, built once per call and handed to OptimalPolicy::new.
+// The optional ones are None when not given and take their defaults in OptimalPolicy::new
+pub struct ModelParams {
+    pub sa_demand_param_one: f64,
+    pub sb_demand_param_one: f64,
+    pub h_s: PerStore,
+    pub h_w: f64,
+    pub c_u_s: PerStore,
+    pub c_p: PerStore,
+    pub c_ts: PerDirection,
+    pub c_ts_fixed: f64,
+    pub p: Option<PerStore>,
+    pub sa_demand_param_two: Option<f64>,
+    pub sb_demand_param_two: Option<f64>,
+    pub distribution: Option<char>,
+    pub max_wh: Option<usize>,
+    pub max_sa: Option<usize>,
+    pub max_sb: Option<usize>,
+    pub gamma: Option<f64>,
+    pub dfw_order: Option<DfwOrder>,
+    pub dfw_threshold: usize,
+    pub substitution: Option<PerStore>,
+    pub online_demand_param_one: Option<f64>,
+    pub online_demand_param_two: Option<f64>,
+    pub c_u_w: f64,
+    pub c_ts_emergency: Option<PerDirection>,
+}
+
+impl ModelParams {
+    // Takes the parameters in field order, so the Python entry points don't each spell out the struct
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         sa_demand_param_one: f64,
         sb_demand_param_one: f64,
-        h_s: f64,
+        h_s: PerStore,
         h_w: f64,
-        c_u_s: f64,
-        c_p: f64,
-        c_ts: f64,
-        base_stock_a: usize,
-        base_stock_b: usize,
-        p: Option<f64>,
+        c_u_s: PerStore,
+        c_p: PerStore,
+        c_ts: PerDirection,
+        c_ts_fixed: f64,
+        p: Option<PerStore>,
         sa_demand_param_two: Option<f64>,
         sb_demand_param_two: Option<f64>,
         distribution: Option<char>,
@@ -51,9 +161,74 @@ impl OptimalPolicy {
         max_sa: Option<usize>,
         max_sb: Option<usize>,
         gamma: Option<f64>,
+        dfw_order: Option<DfwOrder>,
+        dfw_threshold: usize,
+        substitution: Option<PerStore>,
+        online_demand_param_one: Option<f64>,
+        online_demand_param_two: Option<f64>,
+        c_u_w: f64,
+        c_ts_emergency: Option<PerDirection>,
     ) -> Self {
+        ModelParams {
+            sa_demand_param_one,
+            sb_demand_param_one,
+            h_s,
+            h_w,
+            c_u_s,
+            c_p,
+            c_ts,
+            c_ts_fixed,
+            p,
+            sa_demand_param_two,
+            sb_demand_param_two,
+            distribution,
+            max_wh,
+            max_sa,
+            max_sb,
+            gamma,
+            dfw_order,
+            dfw_threshold,
+            substitution,
+            online_demand_param_one,
+            online_demand_param_two,
+            c_u_w,
+            c_ts_emergency,
+        }
+    }
+}
+
+impl OptimalPolicy {
+    // base_stock is the (store A, store B) base-stock level, only ESR uses it
+    pub fn new(model: &ModelParams, base_stock: (usize, usize)) -> Self {
+        let &ModelParams {
+            sa_demand_param_one,
+            sb_demand_param_one,
+            h_s,
+            h_w,
+            c_u_s,
+            c_p,
+            c_ts,
+            c_ts_fixed,
+            p,
+            sa_demand_param_two,
+            sb_demand_param_two,
+            distribution,
+            max_wh,
+            max_sa,
+            max_sb,
+            gamma,
+            dfw_order,
+            dfw_threshold,
+            substitution,
+            online_demand_param_one,
+            online_demand_param_two,
+            c_u_w,
+            c_ts_emergency,
+        } = model;
+        let (base_stock_a, base_stock_b) = base_stock;
         // Assign optional parameters
-        let p: f64 = p.unwrap_or(0.8);
+        let p = p.unwrap_or(PerStore(0.8, 0.8));
+        let substitution = substitution.unwrap_or(PerStore(0.0, 0.0));
         let distribution: char = distribution.unwrap_or('P');
         let da_pmf: [f64; crate::D_MAX] =
             rust::distributions::generate_distributions::distribution_pmf(
@@ -67,23 +242,52 @@ impl OptimalPolicy {
                 sb_demand_param_one,
                 sb_demand_param_two,
             );
-        let mut binom_pmf = [[0.0; crate::D_MAX + 1]; crate::D_MAX + 1];
-        for i in 0..crate::D_MAX + 1 {
-            let binom_distr = Binomial::new(p, i as u64).unwrap();
-            for j in 0..crate::D_MAX + 1 {
-                binom_pmf[i][j] = (binom_distr.pmf(j as u64)) as f64;
+        let dw_pmf: [f64; crate::D_MAX] = match online_demand_param_one {
+            Some(online_demand_param_one) => {
+                rust::distributions::generate_distributions::distribution_pmf(
+                    distribution,
+                    online_demand_param_one,
+                    online_demand_param_two,
+                )
             }
-        }
+            None => {
+                let mut dw_pmf = [0.0; crate::D_MAX];
+                dw_pmf[0] = 1.0;
+                dw_pmf
+            }
+        };
+        let binom_table = |p: f64| {
+            let mut binom_pmf = [[0.0; crate::D_MAX + 1]; crate::D_MAX + 1];
+            for i in 0..crate::D_MAX + 1 {
+                let binom_distr = Binomial::new(p, i as u64).unwrap();
+                for j in 0..crate::D_MAX + 1 {
+                    binom_pmf[i][j] = (binom_distr.pmf(j as u64)) as f64;
+                }
+            }
+            binom_pmf
+        };
 
         OptimalPolicy {
-            h_s,
+            h_s: (h_s.0, h_s.1),
             h_w,
-            c_u_s,
-            c_p,
-            c_ts,
+            c_u_s: (c_u_s.0, c_u_s.1),
+            c_p: (c_p.0, c_p.1),
+            c_ts: (c_ts.0, c_ts.1),
+            c_ts_fixed,
             da_pmf,
             db_pmf,
-            binom_pmf,
+            dw_pmf,
+            c_u_w,
+            binom_pmf_a: binom_table(p.0),
+            binom_pmf_b: binom_table(p.1),
+            substitution: (substitution.0, substitution.1),
+            substitution_pmf_a: binom_table(substitution.0),
+            substitution_pmf_b: binom_table(substitution.1),
+            recovery_pmf_a: binom_table(substitution.0 + (1.0 - substitution.0) * p.0),
+            recovery_pmf_b: binom_table(substitution.1 + (1.0 - substitution.1) * p.1),
+            dfw_order: dfw_order.unwrap_or(DfwOrder::AFirst),
+            c_ts_emergency: c_ts_emergency.map(|c_ts_emergency| (c_ts_emergency.0, c_ts_emergency.1)),
+            dfw_threshold,
             max_wh: max_wh.unwrap_or(20),
             max_sa: max_sa.unwrap_or(10),
             max_sb: max_sb.unwrap_or(10),
@@ -93,6 +297,287 @@ impl OptimalPolicy {
         }
     }
 
+    // Demand pmf, holding cost, lost sales cost, DFW cost and DFW acceptance table of store 1 (A) or 2 (B)
+    pub fn store_params(&self, store: usize) -> (&[f64; crate::D_MAX], f64, f64, f64, &BinomTable) {
+        if store == 1 {
+            (&self.da_pmf, self.h_s.0, self.c_u_s.0, self.c_p.0, &self.binom_pmf_a)
+        } else {
+            (&self.db_pmf, self.h_s.1, self.c_u_s.1, self.c_p.1, &self.binom_pmf_b)
+        }
+    }
+
+    // Cost of transhipping t_a_to_b units from A to B and t_b_to_a units from B to A
+    pub fn transhipment_cost(&self, t_a_to_b: usize, t_b_to_a: usize) -> f64 {
+        let fixed = if t_a_to_b + t_b_to_a > 0 {
+            self.c_ts_fixed
+        } else {
+            0.0
+        };
+        self.c_ts.0 * t_a_to_b as f64 + self.c_ts.1 * t_b_to_a as f64 + fixed
+    }
+
+    // Per-unit cost of transhipping out of store 1 (A) or 2 (B)
+    pub fn unit_transhipment_cost(&self, source: usize) -> f64 {
+        if source == 1 {
+            self.c_ts.0
+        } else {
+            self.c_ts.1
+        }
+    }
+
+    // DFW acceptance table of store 1 (A) or 2 (B)
+    pub fn dfw_acceptance(&self, store: usize) -> &BinomTable {
+        self.store_params(store).4
+    }
+
+    // Table of unmet customers at store 1 (A) or 2 (B) that are not lost, for the one step ahead heuristics.
+    // A customer walking to the other store is taken as served there, so each is recovered with probability s + (1 - s) p
+    pub fn shortage_recovery(&self, store: usize) -> &BinomTable {
+        if store == 1 {
+            &self.recovery_pmf_a
+        } else {
+            &self.recovery_pmf_b
+        }
+    }
+
+    // Expected cost of an unmet customer at store 1 (A) or 2 (B) counted as recovered by shortage_recovery.
+    // Walkers are served by the other store at no cost to this one, only the share accepting DFW costs c_p,
+    // as in store_outcome_cost
+    fn recovered_unit_cost(&self, store: usize) -> f64 {
+        let (_, _, _, c_p, acceptance) = self.store_params(store);
+        let substitution = if store == 1 { self.substitution.0 } else { self.substitution.1 };
+        // Probability a lone unmet customer accepts DFW
+        let p = acceptance[1][1];
+        let recovered = substitution + (1.0 - substitution) * p;
+        if recovered > 0.0 {
+            c_p * ((1.0 - substitution) * p / recovered)
+        } else {
+            0.0
+        }
+    }
+
+    // Calls f(demand, dfw, online, emergency, probability) for every demand faced by (store A, store B) once unmet customers
+    // have walked to the other store's leftover stock, the units (j, k) shipped direct from the warehouse to the customers store A and B
+    // could not serve from the stock in state, following dfw_order and keeping dfw_threshold units back, the online orders (filled, lost) and the units
+    // emergency transhipped (A->B, B->A) after demand. Online orders are filled from the warehouse first, DFW gets what they leave.
+    // Like walkers, emergency transhipped units count as demand at the store that sent them.
+    // dfw_threshold is usually the model's own, the solvers that choose a threshold per state pass theirs
+    pub fn for_each_demand_outcome_rationed(
+        &self,
+        state: (usize, usize, usize),
+        dfw_threshold: usize,
+        mut f: impl FnMut((usize, usize), (usize, usize), (usize, usize), (usize, usize), f64),
+    ) {
+        self.demand_outcome_stages(state, dfw_threshold, &mut None, |_, outcome, prob| {
+            let (demand, dfw, online, emergency) = outcome;
+            f(demand, dfw, online, emergency, prob)
+        });
+    }
+
+    // Draws one (demand, dfw, online, emergency) outcome of for_each_demand_outcome_rationed under the model's dfw_threshold,
+    // running the same stages with each one drawn instead of enumerated
+    pub fn sample_demand_outcome(
+        &self,
+        state: (usize, usize, usize),
+        rng: &mut impl Rng,
+    ) -> DemandOutcome {
+        let mut drawn = None;
+        self.demand_outcome_stages(state, self.dfw_threshold, &mut Some(rng), |_, outcome, _| {
+            drawn = Some(outcome)
+        });
+        drawn.unwrap()
+    }
+
+    // The stages of a period shared by for_each_demand_outcome_rationed and sample_demand_outcome: online orders,
+    // store demand, walkers, then DFW and emergency transhipments in the order emergency_before_dfw sets.
+    // Calls f once per outcome with its probability, or once with the drawn outcome when sampling
+    fn demand_outcome_stages(
+        &self,
+        state: (usize, usize, usize),
+        dfw_threshold: usize,
+        rng: &mut Sampler,
+        mut f: impl FnMut(&mut Sampler, DemandOutcome, f64),
+    ) {
+        branch(&self.dw_pmf, rng, |rng, dw_val, dw_prob| {
+            let online = (min(dw_val, state.0), dw_val.saturating_sub(state.0));
+            let dfw_stock = (state.0 - online.0).saturating_sub(dfw_threshold);
+            branch(&self.da_pmf, rng, |rng, da_val, da_prob| {
+                branch(&self.db_pmf, rng, |rng, db_val, db_prob| {
+                    let prob = dw_prob * da_prob * db_prob;
+                    self.for_each_substitution(state, (da_val, db_val), rng, |rng, demand, sub_prob| {
+                        let prob = prob * sub_prob;
+                        if self.emergency_before_dfw(state, demand) {
+                            let (demand, emergency) = self.emergency_transhipment(state, demand, (0, 0));
+                            let excess = (demand.0.saturating_sub(state.1), demand.1.saturating_sub(state.2));
+                            self.for_each_dfw_outcome(dfw_stock, excess, rng, |rng, dfw, dfw_prob| {
+                                f(rng, (demand, dfw, online, emergency), prob * dfw_prob)
+                            });
+                        } else {
+                            let excess = (demand.0.saturating_sub(state.1), demand.1.saturating_sub(state.2));
+                            self.for_each_dfw_outcome(dfw_stock, excess, rng, |rng, dfw, dfw_prob| {
+                                let (demand, emergency) = self.emergency_transhipment(state, demand, dfw);
+                                f(rng, (demand, dfw, online, emergency), prob * dfw_prob)
+                            });
+                        }
+                    });
+                });
+            });
+        });
+    }
+
+    // Calls f(demand, probability) for the demand each store faces once the customers one store could not serve
+    // have walked to the other store, those finding stock there are served by it and the rest go on to DFW
+    fn for_each_substitution(
+        &self,
+        state: (usize, usize, usize),
+        demand: (usize, usize),
+        rng: &mut Sampler,
+        mut f: impl FnMut(&mut Sampler, (usize, usize), f64),
+    ) {
+        let excess = (demand.0.saturating_sub(state.1), demand.1.saturating_sub(state.2));
+        let leftover = (state.1.saturating_sub(demand.0), state.2.saturating_sub(demand.1));
+        if excess.0 > 0 && leftover.1 > 0 && self.substitution.0 > 0.0 {
+            branch(&self.substitution_pmf_a[excess.0][..=excess.0], rng, |rng, walk, prob| {
+                let served = min(walk, leftover.1);
+                f(rng, (demand.0 - served, demand.1 + served), prob);
+            });
+        } else if excess.1 > 0 && leftover.0 > 0 && self.substitution.1 > 0.0 {
+            branch(&self.substitution_pmf_b[excess.1][..=excess.1], rng, |rng, walk, prob| {
+                let served = min(walk, leftover.0);
+                f(rng, (demand.0 + served, demand.1 - served), prob);
+            });
+        } else {
+            f(rng, demand, 1.0);
+        }
+    }
+
+    // Whether emergency transhipments serve the short store's customers before DFW, which they do when they cost no more per unit
+    fn emergency_before_dfw(&self, state: (usize, usize, usize), demand: (usize, usize)) -> bool {
+        match self.c_ts_emergency {
+            Some(c_ts_emergency) if demand.0 > state.1 => c_ts_emergency.1 <= self.c_p.0,
+            Some(c_ts_emergency) => c_ts_emergency.0 <= self.c_p.1,
+            None => true,
+        }
+    }
+
+    // Demand once the customers still short after the dfw units (j, k) are sent the other store's leftover stock,
+    // and the units emergency transhipped (A->B, B->A). Nothing moves without c_ts_emergency or when it is not below c_u_s
+    fn emergency_transhipment(
+        &self,
+        state: (usize, usize, usize),
+        demand: (usize, usize),
+        dfw: (usize, usize),
+    ) -> ((usize, usize), (usize, usize)) {
+        let Some(c_ts_emergency) = self.c_ts_emergency else {
+            return (demand, (0, 0));
+        };
+        let short = (
+            demand.0.saturating_sub(state.1) - dfw.0,
+            demand.1.saturating_sub(state.2) - dfw.1,
+        );
+        let leftover = (state.1.saturating_sub(demand.0), state.2.saturating_sub(demand.1));
+        // Only worth sending while it costs less than losing the sale
+        let a_to_b = if c_ts_emergency.0 < self.c_u_s.1 { min(short.1, leftover.0) } else { 0 };
+        let b_to_a = if c_ts_emergency.1 < self.c_u_s.0 { min(short.0, leftover.1) } else { 0 };
+        (
+            (demand.0 - b_to_a + a_to_b, demand.1 - a_to_b + b_to_a),
+            (a_to_b, b_to_a),
+        )
+    }
+
+    // Calls f((j, k), probability) for the units shipped direct from a warehouse holding wh units
+    // when (store A, store B) are short of excess customers
+    fn for_each_dfw_outcome(
+        &self,
+        wh: usize,
+        excess: (usize, usize),
+        rng: &mut Sampler,
+        mut f: impl FnMut(&mut Sampler, (usize, usize), f64),
+    ) {
+        // Nobody short, the common case
+        if excess == (0, 0) {
+            return f(rng, (0, 0), 1.0);
+        }
+        match self.dfw_order {
+            DfwOrder::AFirst => self.sequential_dfw(wh, excess, 1, 1.0, rng, &mut f),
+            DfwOrder::BFirst => self.sequential_dfw(wh, excess, 2, 1.0, rng, &mut f),
+            // A fair coin picks the store served first
+            DfwOrder::Random => branch(&[0.5, 0.5], rng, |rng, coin, prob| {
+                self.sequential_dfw(wh, excess, coin + 1, prob, rng, &mut f)
+            }),
+            DfwOrder::Proportional => self.proportional_dfw(wh, excess, rng, &mut f),
+        }
+    }
+
+    // The first store's customers are offered warehouse stock, then the second store's are offered what is left
+    fn sequential_dfw(
+        &self,
+        wh: usize,
+        excess: (usize, usize),
+        first: usize,
+        weight: f64,
+        rng: &mut Sampler,
+        f: &mut impl FnMut(&mut Sampler, (usize, usize), f64),
+    ) {
+        let second = if first == 1 { 2 } else { 1 };
+        let (excess_first, excess_second) = if first == 1 {
+            excess
+        } else {
+            (excess.1, excess.0)
+        };
+        let (acceptance_first, acceptance_second) = (self.dfw_acceptance(first), self.dfw_acceptance(second));
+        let max_beta_first = min(excess_first, wh);
+        branch(&acceptance_first[max_beta_first][..=max_beta_first], rng, |rng, j, accept_first| {
+            let max_beta_second = min(excess_second, wh - j);
+            branch(&acceptance_second[max_beta_second][..=max_beta_second], rng, |rng, k, accept_second| {
+                let prob = weight * accept_first * accept_second;
+                if first == 1 {
+                    f(rng, (j, k), prob)
+                } else {
+                    f(rng, (k, j), prob)
+                }
+            });
+        });
+    }
+
+    // Every customer short decides on DFW, if the warehouse can't serve all that accept it is split in proportion
+    // to them, rounding to the larger remainder and with a fair coin when both remainders are equal
+    fn proportional_dfw(
+        &self,
+        wh: usize,
+        excess: (usize, usize),
+        rng: &mut Sampler,
+        f: &mut impl FnMut(&mut Sampler, (usize, usize), f64),
+    ) {
+        branch(&self.binom_pmf_a[excess.0][..=excess.0], rng, |rng, a, prob_a| {
+            branch(&self.binom_pmf_b[excess.1][..=excess.1], rng, |rng, b, prob_b| {
+                let prob = prob_a * prob_b;
+                if a + b <= wh {
+                    return f(rng, (a, b), prob);
+                }
+                let share = (a * wh / (a + b), b * wh / (a + b));
+                if share.0 + share.1 == wh {
+                    return f(rng, share, prob);
+                }
+                let remainder = (a * wh % (a + b), b * wh % (a + b));
+                if remainder.0 > remainder.1 {
+                    f(rng, (share.0 + 1, share.1), prob);
+                } else if remainder.1 > remainder.0 {
+                    f(rng, (share.0, share.1 + 1), prob);
+                } else {
+                    branch(&[0.5, 0.5], rng, |rng, coin, coin_prob| {
+                        let split = if coin == 0 {
+                            (share.0 + 1, share.1)
+                        } else {
+                            (share.0, share.1 + 1)
+                        };
+                        f(rng, split, prob * coin_prob)
+                    });
+                }
+            });
+        });
+    }
+
     // Function to generate the state space
     pub fn construct_state_space_iterator(&self) -> impl Iterator<Item = (usize, usize, usize)> {
         iproduct!(
@@ -166,33 +651,57 @@ impl OptimalPolicy {
         action_space
     }
 
+    // Whether an action is one of generate_action_space(state), without building the whole action space
+    pub fn is_feasible_action(
+        &self,
+        state: (usize, usize, usize),
+        action: (usize, usize, usize, usize, usize),
+    ) -> bool {
+        let (wh_order, order_st_a, order_st_b, t_a_to_b, t_b_to_a) = action;
+        // Transhipments only go one way and can't push the receiving store past the state space
+        let transhipment_ok = (t_a_to_b == 0 && t_b_to_a == 0)
+            || (t_b_to_a == 0 && t_a_to_b <= state.1 && state.2 + t_a_to_b < self.max_sb)
+            || (t_a_to_b == 0 && t_b_to_a <= state.2 && state.1 + t_b_to_a < self.max_sa);
+        if !transhipment_ok {
+            return false;
+        }
+        let new_state = (
+            state.0,
+            state.1 - t_a_to_b + t_b_to_a,
+            state.2 - t_b_to_a + t_a_to_b,
+        );
+        // Store orders come out of warehouse stock and the warehouse can't exceed the state space once its order arrives
+        order_st_a + new_state.1 < self.max_sa
+            && order_st_b + new_state.2 < self.max_sb
+            && order_st_a + order_st_b <= new_state.0
+            && wh_order + (new_state.0 - order_st_a - order_st_b) < self.max_wh
+    }
+
     pub fn expectation_warehouse(&self, state: (usize, usize, usize)) -> PyResult<f64> {
+        self.expectation_warehouse_rationed(state, self.dfw_threshold)
+    }
+
+    pub fn expectation_warehouse_rationed(
+        &self,
+        state: (usize, usize, usize),
+        dfw_threshold: usize,
+    ) -> PyResult<f64> {
         let mut exp: f64 = 0.0;
         // First stage shortage
-        for (da_val, da_pmf_i) in self.da_pmf.iter().enumerate() {
-            let max_beta_sa: usize =
-                min(max(da_val as isize - state.1 as isize, 0), state.0 as isize) as usize;
-            for (db_val, db_pmf_i) in self.db_pmf.iter().enumerate() {
-                for j in 0..max_beta_sa + 1 {
-                    let max_beta_sb: usize = min(
-                        max(db_val as isize - state.2 as isize, 0),
-                        (state.0 - j) as isize,
-                    ) as usize;
-                    for k in 0..max_beta_sb + 1 {
-                        let fs = da_pmf_i
-                            * db_pmf_i
-                            * self.binom_pmf[max_beta_sa][j]
-                            * self.binom_pmf[max_beta_sb][k]
-                            * self.h_w
-                            * (state.0 - (j + k)) as f64;
-                        exp += fs;
-                    }
-                }
-            }
-        }
+        self.for_each_demand_outcome_rationed(state, dfw_threshold, |demand, dfw, online, emergency, prob| {
+            exp += prob * self.warehouse_outcome_cost(state, (demand, dfw, online, emergency));
+        });
         Ok(exp)
     }
 
+    // Warehouse cost of one outcome of for_each_demand_outcome_rationed in a post-action state
+    pub fn warehouse_outcome_cost(&self, state: (usize, usize, usize), outcome: DemandOutcome) -> f64 {
+        let (_demand, (j, k), online, _emergency) = outcome;
+        self.h_w * (state.0 - (online.0 + j + k)) as f64
+            // Online orders the warehouse could not fill
+            + self.c_u_w * online.1 as f64
+    }
+
     pub fn expectation_all_stores(&self) -> HashMap<(usize, usize, usize), f64> {
         let mut state_space = HashMap::new();
         let state_space_iterator = self.construct_state_space_iterator();
@@ -220,6 +729,7 @@ impl OptimalPolicy {
         &self,
         store_a_mean: f64,
         store_b_mean: f64,
+        split_by_mean: bool,
     ) -> (
         // Arguments are warehouse level, store state, store number
         HashMap<(usize, usize, usize), (f64, f64, f64)>,
@@ -242,6 +752,11 @@ impl OptimalPolicy {
                     max_q_b += 1.0;
                 }
             }
+            // Without splitting either store may order the whole warehouse, the caller rations the orders
+            if !split_by_mean {
+                max_q_a = wh as f64;
+                max_q_b = wh as f64;
+            }
             // Store a
             for st_a in 0..self.max_sa {
                 let (exp, exp_first_stage, q) =
@@ -323,78 +838,51 @@ impl OptimalPolicy {
     }
 
     pub fn expectation_store(&self, state: (usize, usize, usize)) -> PyResult<f64> {
+        self.expectation_store_rationed(state, self.dfw_threshold)
+    }
+
+    pub fn expectation_store_rationed(
+        &self,
+        state: (usize, usize, usize),
+        dfw_threshold: usize,
+    ) -> PyResult<f64> {
         let mut exp = 0.0;
         // Calculate the expectation
-        // Due to fulfilment of excess demand being indifferent as to the location (since costs and lead-time are identical) we deal with store 1 first then store 2.
-        for (da_val, da_pmf_i) in self.da_pmf.iter().enumerate() {
-            // Add holding cost of excess demand
-            if (state.1 as isize - da_val as isize) >= 0 {
-                exp += da_pmf_i * self.h_s * (state.1 as f64 - da_val as f64);
-            } else {
-                let excess = da_val as isize - state.1 as isize;
-                // See how much stock we can fulfil from the warehouse
-                let max_beta_sa: usize = min(max(excess, 0), state.0 as isize) as usize;
-                for j in 0..max_beta_sa + 1 {
-                    let unfulfilled = excess - j as isize;
-                    exp += da_pmf_i
-                        * self.binom_pmf[max_beta_sa][j]
-                        * (self.c_p * j as f64 + unfulfilled as f64 * self.c_u_s) as f64;
-                }
-            }
-        }
-
-        for (db_val, db_pmf_i) in self.db_pmf.iter().enumerate() {
-            // Add holding cost of excess demand
-            if (state.2 as isize - db_val as isize) >= 0 {
-                exp += db_pmf_i * self.h_s * (state.2 as f64 - db_val as f64);
-            } else {
-                // See how much stock we can fulfil from the warehouse
-                // Need to take into accoutn how much we expect to be taken up by store 1
-                for (da_val, da_pmf_i) in self.da_pmf.iter().enumerate() {
-                    if (state.1 as isize - da_val as isize) >= 0 {
-                        // In this case store 1 took no stock from the warehouse
-                        // Carry on with the procedure
-                        let remaining_warehouse_stock = state.0;
-                        let excess = db_val as isize - state.2 as isize;
-                        // See how much stock we can fulfil from the warehouse
-                        let max_beta_sb: usize =
-                            min(max(excess, 0), remaining_warehouse_stock as isize) as usize;
-                        for j in 0..max_beta_sb + 1 {
-                            let unfulfilled = excess - j as isize;
-                            exp += da_pmf_i
-                                * db_pmf_i
-                                * self.binom_pmf[max_beta_sb][j]
-                                * (self.c_p * j as f64 + unfulfilled as f64 * self.c_u_s) as f64;
-                        }
-                    } else {
-                        let excess_s1 = da_val as isize - state.1 as isize;
-                        // See how much stock we can fulfil from the warehouse
-                        let max_beta_sa: usize = min(max(excess_s1, 0), state.0 as isize) as usize;
-                        for j in 0..max_beta_sa + 1 {
-                            // Number of remaining stock we can fulfil from the warehouse
-                            let remaining_warehouse_stock = state.0 - j;
-                            let excess = db_val as isize - state.2 as isize;
-                            // See how much stock we can fulfil from the warehouse
-                            let max_beta_sb: usize =
-                                min(max(excess, 0), remaining_warehouse_stock as isize) as usize;
-                            for k in 0..max_beta_sb + 1 {
-                                let unfulfilled = excess - k as isize;
-                                exp += da_pmf_i
-                                    * db_pmf_i
-                                    * self.binom_pmf[max_beta_sa][j]
-                                    * self.binom_pmf[max_beta_sb][k]
-                                    * (self.c_p * k as f64 + unfulfilled as f64 * self.c_u_s)
-                                        as f64;
-                            }
-                        }
-                    }
-                }
-            }
-        }
+        // Excess demand at both stores competes for the warehouse stock, served in the order set by dfw_order
+        self.for_each_demand_outcome_rationed(state, dfw_threshold, |demand, dfw, online, emergency, prob| {
+            exp += prob * self.store_outcome_cost(state, (demand, dfw, online, emergency));
+        });
 
         Ok(exp)
     }
 
+    // Customers of (store A, store B) lost in one outcome of for_each_demand_outcome_rationed in a post-action state,
+    // the demand faced that neither the store's stock nor DFW met
+    pub fn unfulfilled(&self, state: (usize, usize, usize), outcome: DemandOutcome) -> (usize, usize) {
+        let ((da_val, db_val), (j, k), _online, _emergency) = outcome;
+        (
+            da_val.saturating_sub(state.1) - j,
+            db_val.saturating_sub(state.2) - k,
+        )
+    }
+
+    // Store cost of one outcome of for_each_demand_outcome_rationed in a post-action state
+    pub fn store_outcome_cost(&self, state: (usize, usize, usize), outcome: DemandOutcome) -> f64 {
+        let ((da_val, db_val), (j, k), _online, emergency) = outcome;
+        let unfulfilled = self.unfulfilled(state, outcome);
+        // Holding cost of the stock left after demand
+        let mut cost = self.h_s.0 * state.1.saturating_sub(da_val) as f64
+            + self.h_s.1 * state.2.saturating_sub(db_val) as f64;
+        cost += self.c_p.0 * j as f64
+            + unfulfilled.0 as f64 * self.c_u_s.0
+            + self.c_p.1 * k as f64
+            + unfulfilled.1 as f64 * self.c_u_s.1;
+        if let Some(c_ts_emergency) = self.c_ts_emergency {
+            cost += c_ts_emergency.0 * emergency.0 as f64 + c_ts_emergency.1 * emergency.1 as f64;
+        }
+        cost
+    }
+
     // For ESR policy
     // Assumes stationary distribution
     pub fn one_step_ahead(
@@ -411,19 +899,15 @@ impl OptimalPolicy {
         let mut exp: f64 = 0.0;
         let mut exp_first_stage: f64 = 0.0;
 
-        // get which stores pmf to use
-        let d_pmf = if store == 1 {
-            &self.da_pmf
-        } else {
-            &self.db_pmf
-        };
+        // get which stores pmf and DFW acceptance to use
+        let d_pmf = self.store_params(store).0;
 
         for (d1_val, d1_pmf_i) in d_pmf.iter().enumerate() {
             // First stage shortage
             let shortage_p1: usize = max(d1_val as isize - x as isize, 0) as usize;
             for j in 0..shortage_p1 + 1 {
                 let fs = d1_pmf_i
-                    * self.binom_pmf[shortage_p1][j]
+                    * self.shortage_recovery(store)[shortage_p1][j]
                     * (f64::max(d1_val as f64 - x as f64, 0.0) - j as f64);
                 exp_first_stage += fs;
                 exp += fs;
@@ -444,7 +928,7 @@ impl OptimalPolicy {
                 for j in 0..shortage_p2 + 1 {
                     exp += d1_pmf_i
                         * d2_pmf_i
-                        * self.binom_pmf[shortage_p2][j]
+                        * self.shortage_recovery(store)[shortage_p2][j]
                         * (f64::max(
                             d2_val as f64 - f64::max(x as f64 - d1_val as f64, 0.0) - q,
                             0.0,
@@ -468,15 +952,16 @@ impl OptimalPolicy {
         let mut exp: f64 = 0.0;
         let mut exp_first_stage: f64 = 0.0;
 
-        // get which stores pmf to use
-        let d_pmf = if store == 1 {
-            &self.da_pmf
-        } else {
-            &self.db_pmf
-        };
+        // get which stores pmf and DFW acceptance to use
+        let d_pmf = self.store_params(store).0;
 
-        // Find the optimal q (if terminal we make no order as no demand is observed in next period)
-        let q: f64 = self.minimise_q_search(max_q, x, d_pmf, terminal_period);
+        // Find the optimal q (if terminal we make no order as no demand is observed in next period).
+        // The cost is convex in q, so cutting it back to what the store can hold gives the best order that fits
+        let max_s = if store == 1 { self.max_sa } else { self.max_sb };
+        let q: f64 = f64::min(
+            self.minimise_q_search(max_q, x, store, terminal_period),
+            (max_s - 1 - x) as f64,
+        );
 
         // First stage shortage
         for (d1_val, d1_pmf_i) in d_pmf.iter().enumerate() {
@@ -484,7 +969,7 @@ impl OptimalPolicy {
             let shortage_p1: usize = max(d1_val as isize - x as isize, 0) as usize;
             for j in 0..shortage_p1 + 1 {
                 let fs = d1_pmf_i
-                    * self.binom_pmf[shortage_p1][j]
+                    * self.shortage_recovery(store)[shortage_p1][j]
                     * (f64::max(d1_val as f64 - x as f64, 0.0) - j as f64);
                 exp_first_stage += fs;
                 exp += fs;
@@ -506,7 +991,7 @@ impl OptimalPolicy {
                 for j in 0..shortage_p2 + 1 {
                     exp += d1_pmf_i
                         * d2_pmf_i
-                        * self.binom_pmf[shortage_p2][j]
+                        * self.shortage_recovery(store)[shortage_p2][j]
                         * (f64::max(
                             d2_val as f64 - f64::max(x as f64 - d1_val as f64, 0.0) - q,
                             0.0,
@@ -518,7 +1003,7 @@ impl OptimalPolicy {
         (exp_first_stage, exp, q)
     }
 
-    pub fn minimise_q_search(&self, max_q: f64, x: usize, d_pmf: &[f64], terminal: bool) -> f64 {
+    pub fn minimise_q_search(&self, max_q: f64, x: usize, store: usize, terminal: bool) -> f64 {
         // Pick mid-point
         let mut q_mid = f64::floor(max_q / 2.0);
 
@@ -526,9 +1011,9 @@ impl OptimalPolicy {
             return 0.0;
         }
 
-        let q_mid_res = self.lookahead_q_expectation(x, q_mid, d_pmf, terminal);
-        let q_plus_1 = self.lookahead_q_expectation(x, q_mid + 1.0, d_pmf,terminal);
-        let q_minus_1 = self.lookahead_q_expectation(x, q_mid - 1.0, d_pmf,terminal);
+        let q_mid_res = self.lookahead_q_expectation(x, q_mid, store, terminal);
+        let q_plus_1 = self.lookahead_q_expectation(x, q_mid + 1.0, store, terminal);
+        let q_minus_1 = self.lookahead_q_expectation(x, q_mid - 1.0, store, terminal);
 
         let best = f64::min(q_mid_res, f64::min(q_plus_1, q_minus_1));
         if best == q_mid_res {
@@ -539,10 +1024,10 @@ impl OptimalPolicy {
         let dir: f64 = (if best == q_plus_1 { 1.0 } else { -1.0 } as f64);
 
         loop {
-            let q_mid_res = self.lookahead_q_expectation(x, q_mid, d_pmf,terminal);
+            let q_mid_res = self.lookahead_q_expectation(x, q_mid, store, terminal);
             q_mid += dir;
 
-            let q_mid_res_change = self.lookahead_q_expectation(x, q_mid, d_pmf, terminal);
+            let q_mid_res_change = self.lookahead_q_expectation(x, q_mid, store, terminal);
             if q_mid_res_change > q_mid_res {
                 return q_mid - dir;
             }
@@ -557,7 +1042,9 @@ impl OptimalPolicy {
         }
     }
 
-    fn lookahead_q_expectation(&self, x: usize, q: f64, d_pmf: &[f64],terminal: bool) -> f64 {
+    pub fn lookahead_q_expectation(&self, x: usize, q: f64, store: usize, terminal: bool) -> f64 {
+        let (d_pmf, h_s, c_u_s, _, _) = self.store_params(store);
+        let c_recovered = self.recovered_unit_cost(store);
         // Calculate second stage expectation balancing shortage and holding costs
         let mut exp: f64 = 0.0;
         for (d1_val, d1_pmf_i) in d_pmf.iter().enumerate() {
@@ -579,12 +1066,381 @@ impl OptimalPolicy {
                 for j in 0..shortage_p2 + 1 {
                     exp += d1_pmf_i
                         * d2_pmf_i
-                        * self.binom_pmf[shortage_p2][j]
-                        * (self.c_p * j as f64 + self.c_u_s * (shortage_p2 - j) as f64);
+                        * self.shortage_recovery(store)[shortage_p2][j]
+                        * (c_recovered * j as f64 + c_u_s * (shortage_p2 - j) as f64);
                 }
-                exp += d1_pmf_i * d2_pmf_i * self.h_s * f64::max(x_2 - d2_val as f64, 0.0);
+                exp += d1_pmf_i * d2_pmf_i * h_s * f64::max(x_2 - d2_val as f64, 0.0);
             }
         }
         return exp;
     }
 }
+
+// Calls f(index, probability) for every index of pmf with any probability, or when sampling once with a drawn index
+// and probability 1. The stages of a period are written once with it and either enumerate or draw their outcomes
+fn branch(pmf: &[f64], rng: &mut Sampler, mut f: impl FnMut(&mut Sampler, usize, f64)) {
+    if let Some(draw) = rng.as_deref_mut() {
+        let value = sample_pmf(pmf, draw);
+        return f(rng, value, 1.0);
+    }
+    for (value, prob) in pmf.iter().enumerate() {
+        if *prob > 0.0 {
+            f(rng, value, *prob);
+        }
+    }
+}
+
+// Draws an index from a pmf, which may be truncated so the draw is scaled by its total mass
+fn sample_pmf(pmf: &[f64], rng: &mut (impl Rng + ?Sized)) -> usize {
+    let total: f64 = pmf.iter().sum();
+    let target = rng.random::<f64>() * total;
+    let mut cumulative = 0.0;
+    for (value, prob) in pmf.iter().enumerate() {
+        cumulative += prob;
+        if cumulative > target {
+            return value;
+        }
+    }
+    // Rounding left the draw past the end, take the last value with any mass
+    pmf.iter().rposition(|prob| *prob > 0.0).unwrap_or(0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+
+    // Small model with every stage of a period switched off, tests turn on what they need
+    pub(crate) fn small_model() -> ModelParams {
+        ModelParams {
+            sa_demand_param_one: 2.0,
+            sb_demand_param_one: 1.5,
+            h_s: PerStore(1.0, 1.0),
+            h_w: 0.5,
+            c_u_s: PerStore(9.0, 9.0),
+            c_p: PerStore(0.5, 0.5),
+            c_ts: PerDirection(1.0, 1.0),
+            c_ts_fixed: 0.0,
+            p: Some(PerStore(0.6, 0.7)),
+            sa_demand_param_two: None,
+            sb_demand_param_two: None,
+            distribution: None,
+            max_wh: Some(6),
+            max_sa: Some(4),
+            max_sb: Some(4),
+            gamma: None,
+            dfw_order: None,
+            dfw_threshold: 0,
+            substitution: None,
+            online_demand_param_one: None,
+            online_demand_param_two: None,
+            c_u_w: 0.0,
+            c_ts_emergency: None,
+        }
+    }
+
+    // Expected (store, warehouse) cost of a post-action state, as expectation_store_rationed and expectation_warehouse_rationed
+    // without going through PyResult
+    fn expected_costs(policy: &OptimalPolicy, state: (usize, usize, usize), dfw_threshold: usize) -> (f64, f64) {
+        let (mut store, mut warehouse) = (0.0, 0.0);
+        policy.for_each_demand_outcome_rationed(state, dfw_threshold, |demand, dfw, online, emergency, prob| {
+            store += prob * policy.store_outcome_cost(state, (demand, dfw, online, emergency));
+            warehouse += prob * policy.warehouse_outcome_cost(state, (demand, dfw, online, emergency));
+        });
+        (store, warehouse)
+    }
+
+    // The store and warehouse expectations before DFW rationing and the DFW orders, store A's customers are offered
+    // warehouse stock first and store B's get what they leave
+    fn baseline_costs(policy: &OptimalPolicy, state: (usize, usize, usize)) -> (f64, f64) {
+        let (da_pmf, h_a, c_u_a, c_p_a, acceptance_a) = policy.store_params(1);
+        let (db_pmf, h_b, c_u_b, c_p_b, acceptance_b) = policy.store_params(2);
+        let (mut store, mut warehouse) = (0.0, 0.0);
+        for (da_val, da_prob) in da_pmf.iter().enumerate() {
+            for (db_val, db_prob) in db_pmf.iter().enumerate() {
+                let excess = (da_val.saturating_sub(state.1), db_val.saturating_sub(state.2));
+                let holding = h_a * state.1.saturating_sub(da_val) as f64 + h_b * state.2.saturating_sub(db_val) as f64;
+                let max_beta_sa = min(excess.0, state.0);
+                for (j, accept_a) in acceptance_a[max_beta_sa][..=max_beta_sa].iter().enumerate() {
+                    let max_beta_sb = min(excess.1, state.0 - j);
+                    for (k, accept_b) in acceptance_b[max_beta_sb][..=max_beta_sb].iter().enumerate() {
+                        let prob = da_prob * db_prob * accept_a * accept_b;
+                        store += prob
+                            * (holding
+                                + c_p_a * j as f64
+                                + c_u_a * (excess.0 - j) as f64
+                                + c_p_b * k as f64
+                                + c_u_b * (excess.1 - k) as f64);
+                        warehouse += prob * policy.h_w * (state.0 - j - k) as f64;
+                    }
+                }
+            }
+        }
+        (store, warehouse)
+    }
+
+    #[test]
+    fn a_first_reproduces_the_baseline_expectations() {
+        let policy = OptimalPolicy::new(&ModelParams { dfw_order: Some(DfwOrder::AFirst), ..small_model() }, (0, 0));
+        for state in policy.construct_state_space_iterator() {
+            let (store, warehouse) = expected_costs(&policy, state, 0);
+            let (baseline_store, baseline_warehouse) = baseline_costs(&policy, state);
+            assert!((store - baseline_store).abs() < 1e-9, "store in state {:?}", state);
+            assert!((warehouse - baseline_warehouse).abs() < 1e-9, "warehouse in state {:?}", state);
+        }
+    }
+
+    #[test]
+    fn dfw_outcome_probabilities_sum_to_one() {
+        let model = ModelParams {
+            substitution: Some(PerStore(0.3, 0.4)),
+            ..small_model()
+        };
+        for dfw_order in [DfwOrder::Random, DfwOrder::Proportional] {
+            let policy = OptimalPolicy::new(&ModelParams { dfw_order: Some(dfw_order), ..model }, (0, 0));
+            // Demand beyond D_MAX is dropped from the pmfs, so the outcomes hold the mass the pmfs keep
+            let mass = policy.da_pmf.iter().sum::<f64>() * policy.db_pmf.iter().sum::<f64>() * policy.dw_pmf.iter().sum::<f64>();
+            for state in policy.construct_state_space_iterator() {
+                for dfw_threshold in 0..=state.0 {
+                    let mut total = 0.0;
+                    policy.for_each_demand_outcome_rationed(state, dfw_threshold, |_, _, _, _, prob| total += prob);
+                    assert!(
+                        (total - mass).abs() < 1e-12,
+                        "{:?} sums to {} in state {:?} with threshold {}",
+                        dfw_order,
+                        total,
+                        state,
+                        dfw_threshold
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn no_emergency_cost_leaves_expectations_unchanged() {
+        let model = ModelParams {
+            substitution: Some(PerStore(0.3, 0.4)),
+            ..small_model()
+        };
+        for dfw_order in [DfwOrder::AFirst, DfwOrder::BFirst, DfwOrder::Random, DfwOrder::Proportional] {
+            let without = OptimalPolicy::new(&ModelParams { dfw_order: Some(dfw_order), ..model }, (0, 0));
+            // Emergency transhipments only go ahead below c_u_s, so at c_u_s they are never used
+            let never_used = OptimalPolicy::new(
+                &ModelParams {
+                    dfw_order: Some(dfw_order),
+                    c_ts_emergency: Some(PerDirection(9.0, 9.0)),
+                    ..model
+                },
+                (0, 0),
+            );
+            for state in without.construct_state_space_iterator() {
+                let mut outcomes: HashMap<DemandOutcome, f64> = HashMap::new();
+                without.for_each_demand_outcome_rationed(state, 0, |demand, dfw, online, emergency, prob| {
+                    assert_eq!(emergency, (0, 0), "{:?} in state {:?}", dfw_order, state);
+                    *outcomes.entry((demand, dfw, online, emergency)).or_insert(0.0) += prob;
+                });
+                never_used.for_each_demand_outcome_rationed(state, 0, |demand, dfw, online, emergency, prob| {
+                    *outcomes.entry((demand, dfw, online, emergency)).or_insert(0.0) -= prob;
+                });
+                assert!(
+                    outcomes.values().all(|prob| prob.abs() < 1e-12),
+                    "{:?} in state {:?}",
+                    dfw_order,
+                    state
+                );
+                let (store, warehouse) = expected_costs(&without, state, 0);
+                let (store_never_used, warehouse_never_used) = expected_costs(&never_used, state, 0);
+                assert!((store - store_never_used).abs() < 1e-9, "{:?} in state {:?}", dfw_order, state);
+                assert!((warehouse - warehouse_never_used).abs() < 1e-9, "{:?} in state {:?}", dfw_order, state);
+            }
+        }
+    }
+
+    #[test]
+    fn feasible_actions_match_the_action_space() {
+        let policy = OptimalPolicy::new(&small_model(), (0, 0));
+        for state in policy.construct_state_space_iterator() {
+            let action_space: HashSet<(usize, usize, usize, usize, usize)> =
+                policy.generate_action_space(state).into_iter().collect();
+            // Every action up to the bounds, infeasible ones included
+            for action in iproduct!(0..policy.max_wh, 0..policy.max_sa, 0..policy.max_sb, 0..policy.max_sa, 0..policy.max_sb) {
+                assert_eq!(
+                    policy.is_feasible_action(state, action),
+                    action_space.contains(&action),
+                    "{:?} in state {:?}",
+                    action,
+                    state
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sampled_outcomes_match_enumerated_probabilities() {
+        let model = ModelParams {
+            substitution: Some(PerStore(0.3, 0.4)),
+            online_demand_param_one: Some(1.0),
+            c_ts_emergency: Some(PerDirection(0.4, 2.0)),
+            ..small_model()
+        };
+        let state = (3, 2, 1);
+        for dfw_order in [DfwOrder::AFirst, DfwOrder::Random, DfwOrder::Proportional] {
+            let policy = OptimalPolicy::new(&ModelParams { dfw_order: Some(dfw_order), ..model }, (0, 0));
+            let mut enumerated: HashMap<DemandOutcome, f64> = HashMap::new();
+            policy.for_each_demand_outcome_rationed(state, 0, |demand, dfw, online, emergency, prob| {
+                *enumerated.entry((demand, dfw, online, emergency)).or_insert(0.0) += prob;
+            });
+            let total: f64 = enumerated.values().sum();
+
+            let draws = 200_000;
+            let mut sampled: HashMap<DemandOutcome, f64> = HashMap::new();
+            let mut rng = StdRng::seed_from_u64(7);
+            for _ in 0..draws {
+                *sampled.entry(policy.sample_demand_outcome(state, &mut rng)).or_insert(0.0) += 1.0;
+            }
+            for outcome in sampled.keys() {
+                assert!(enumerated.contains_key(outcome), "drew {:?} which can't happen", outcome);
+            }
+            for (outcome, prob) in &enumerated {
+                let prob = prob / total;
+                let freq = sampled.get(outcome).copied().unwrap_or(0.0) / draws as f64;
+                // Five standard errors of the frequency
+                let tolerance = 5.0 * (prob * (1.0 - prob) / draws as f64).sqrt() + 1e-4;
+                assert!(
+                    (freq - prob).abs() < tolerance,
+                    "{:?} drawn with frequency {} but has probability {}",
+                    outcome,
+                    freq,
+                    prob
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn swapping_the_stores_mirrors_the_expected_costs() {
+        // Every store parameter differs between the stores
+        let model = ModelParams {
+            h_s: PerStore(1.0, 2.0),
+            c_u_s: PerStore(9.0, 6.0),
+            c_p: PerStore(0.5, 0.8),
+            dfw_order: Some(DfwOrder::AFirst),
+            ..small_model()
+        };
+        let mirrored = ModelParams {
+            sa_demand_param_one: model.sb_demand_param_one,
+            sb_demand_param_one: model.sa_demand_param_one,
+            h_s: PerStore(2.0, 1.0),
+            c_u_s: PerStore(6.0, 9.0),
+            c_p: PerStore(0.8, 0.5),
+            p: Some(PerStore(0.7, 0.6)),
+            dfw_order: Some(DfwOrder::BFirst),
+            ..model
+        };
+        let policy = OptimalPolicy::new(&model, (0, 0));
+        let mirrored_policy = OptimalPolicy::new(&mirrored, (0, 0));
+        for state in policy.construct_state_space_iterator() {
+            let (store, warehouse) = expected_costs(&policy, state, 0);
+            let (mirrored_store, mirrored_warehouse) = expected_costs(&mirrored_policy, (state.0, state.2, state.1), 0);
+            assert!((store - mirrored_store).abs() < 1e-9, "store in state {:?}", state);
+            assert!((warehouse - mirrored_warehouse).abs() < 1e-9, "warehouse in state {:?}", state);
+        }
+        // Holding stock only costs at the store that holds it
+        let cheaper_a = OptimalPolicy::new(&ModelParams { h_s: PerStore(0.0, 2.0), ..model }, (0, 0));
+        let (store, _) = expected_costs(&policy, (0, 3, 0), 0);
+        let (store_cheaper_a, _) = expected_costs(&cheaper_a, (0, 3, 0), 0);
+        assert!(store_cheaper_a < store);
+        let (store, _) = expected_costs(&policy, (0, 0, 3), 0);
+        let (store_cheaper_a, _) = expected_costs(&cheaper_a, (0, 0, 3), 0);
+        assert!((store - store_cheaper_a).abs() < 1e-12);
+    }
+
+    #[test]
+    fn transhipment_costs_follow_the_direction() {
+        let policy = OptimalPolicy::new(
+            &ModelParams { c_ts: PerDirection(1.0, 3.0), c_ts_fixed: 2.0, ..small_model() },
+            (0, 0),
+        );
+        assert_eq!(policy.transhipment_cost(0, 0), 0.0);
+        assert_eq!(policy.transhipment_cost(2, 0), 4.0);
+        assert_eq!(policy.transhipment_cost(0, 2), 8.0);
+        assert_eq!(policy.unit_transhipment_cost(1), 1.0);
+        assert_eq!(policy.unit_transhipment_cost(2), 3.0);
+
+        // One period with no future cost, moving stock to an empty store pays off unless that direction is too dear
+        let best_transhipment = |c_ts: PerDirection, c_ts_fixed: f64, state: (usize, usize, usize)| {
+            let policy = OptimalPolicy::new(&ModelParams { c_ts, c_ts_fixed, ..small_model() }, (0, 0));
+            let v_plus_1 = policy.construct_state_space_iterator().map(|state| (state, 0.0)).collect();
+            let (action, _) = crate::rust::value_function::value_function_optimal_pol(
+                &policy,
+                state,
+                &v_plus_1,
+                &policy.generate_action_space(state),
+                &policy.expectation_all_stores(),
+                &policy.expectation_all_warehouse(),
+            );
+            (action.3, action.4)
+        };
+        assert!(best_transhipment(PerDirection(1.0, 1.0), 0.0, (0, 3, 0)).0 > 0);
+        assert!(best_transhipment(PerDirection(1.0, 1.0), 0.0, (0, 0, 3)).1 > 0);
+        assert!(best_transhipment(PerDirection(1.0, 20.0), 0.0, (0, 3, 0)).0 > 0);
+        assert_eq!(best_transhipment(PerDirection(1.0, 20.0), 0.0, (0, 0, 3)), (0, 0));
+        assert_eq!(best_transhipment(PerDirection(1.0, 1.0), 50.0, (0, 3, 0)), (0, 0));
+    }
+
+    #[test]
+    fn substitution_pools_the_store_stock() {
+        // Nobody walking is the baseline
+        let policy = OptimalPolicy::new(
+            &ModelParams { substitution: Some(PerStore(0.0, 0.0)), dfw_order: Some(DfwOrder::AFirst), ..small_model() },
+            (0, 0),
+        );
+        for state in policy.construct_state_space_iterator() {
+            let (store, warehouse) = expected_costs(&policy, state, 0);
+            let (baseline_store, baseline_warehouse) = baseline_costs(&policy, state);
+            assert!((store - baseline_store).abs() < 1e-9, "store in state {:?}", state);
+            assert!((warehouse - baseline_warehouse).abs() < 1e-9, "warehouse in state {:?}", state);
+        }
+
+        // With everyone walking and an empty warehouse, the two stores act as one holding their combined stock
+        let policy = OptimalPolicy::new(&ModelParams { substitution: Some(PerStore(1.0, 1.0)), ..small_model() }, (0, 0));
+        for (sa, sb) in [(0usize, 3usize), (3, 0), (2, 1), (3, 3)] {
+            let stock = sa + sb;
+            let mut pooled = 0.0;
+            for (da_val, da_prob) in policy.da_pmf.iter().enumerate() {
+                for (db_val, db_prob) in policy.db_pmf.iter().enumerate() {
+                    let demand = da_val + db_val;
+                    pooled += da_prob
+                        * db_prob
+                        * (stock.saturating_sub(demand) as f64 + 9.0 * demand.saturating_sub(stock) as f64);
+                }
+            }
+            let (store, _) = expected_costs(&policy, (0, sa, sb), 0);
+            assert!((store - pooled).abs() < 1e-9, "stores ({}, {}) cost {} pooled {}", sa, sb, store, pooled);
+        }
+    }
+
+    #[test]
+    fn online_orders_are_served_from_the_warehouse() {
+        // Nobody accepts DFW, so the warehouse only serves online orders and the stores are left as they were
+        let offline = ModelParams { p: Some(PerStore(0.0, 0.0)), c_u_w: 4.0, ..small_model() };
+        let online = ModelParams { online_demand_param_one: Some(1.0), ..offline };
+        let offline_policy = OptimalPolicy::new(&offline, (0, 0));
+        let online_policy = OptimalPolicy::new(&online, (0, 0));
+        for state in [(0usize, 2, 1), (1, 0, 0), (3, 3, 3), (5, 1, 2)] {
+            let mut warehouse = 0.0;
+            for (dw_val, dw_prob) in online_policy.dw_pmf.iter().enumerate() {
+                warehouse += dw_prob
+                    * (0.5 * state.0.saturating_sub(dw_val) as f64 + 4.0 * dw_val.saturating_sub(state.0) as f64);
+            }
+            let (store_online, warehouse_online) = expected_costs(&online_policy, state, 0);
+            let (store_offline, warehouse_offline) = expected_costs(&offline_policy, state, 0);
+            assert!((warehouse_online - warehouse).abs() < 1e-9, "state {:?}", state);
+            assert!((warehouse_offline - 0.5 * state.0 as f64).abs() < 1e-9, "state {:?}", state);
+            assert!((store_online - store_offline).abs() < 1e-9, "state {:?}", state);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Progress reporting for the solvers. Solvers stay silent unless a reporter is given.
+use crate::rust;
+use pyo3::prelude::*;
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+pub struct ProgressUpdate {
+    pub period: usize,      // Period currently being solved
+    pub states_done: usize, // States solved so far in this period
+    pub elapsed: f64,       // Seconds since the solve started
+    pub eta: Option<f64>,   // Estimated seconds remaining (None until some work is done)
+}
+
+// Implement this to receive progress from the library API.
+// Returning an error stops the solve.
+pub trait ProgressReporter: Send + Sync {
+    fn report(&self, update: &ProgressUpdate) -> PyResult<()>;
+}
+
+// Python callable progress(period, states_done, elapsed, eta)
+pub struct PyProgressCallback {
+    callback: PyObject,
+}
+
+impl ProgressReporter for PyProgressCallback {
+    fn report(&self, update: &ProgressUpdate) -> PyResult<()> {
+        Python::with_gil(|py| {
+            self.callback
+                .call1(
+                    py,
+                    (update.period, update.states_done, update.elapsed, update.eta),
+                )
+                .map(|_| ())
+        })
+    }
+}
+
+// Keeps track of how much of the backward induction is done and forwards updates to the reporter
+pub struct ProgressTracker {
+    reporter: Option<Box<dyn ProgressReporter>>,
+    start: Instant,
+    decision_periods: usize,
+    states_per_period: usize,
+}
+
+impl ProgressTracker {
+    pub fn new(
+        reporter: Option<Box<dyn ProgressReporter>>,
+        decision_periods: usize,
+        states_per_period: usize,
+    ) -> Self {
+        ProgressTracker {
+            reporter,
+            start: Instant::now(),
+            decision_periods,
+            states_per_period,
+        }
+    }
+
+    pub fn from_py(
+        callback: Option<PyObject>,
+        decision_periods: usize,
+        states_per_period: usize,
+    ) -> Self {
+        let reporter = callback.map(|callback| {
+            Box::new(PyProgressCallback { callback }) as Box<dyn ProgressReporter>
+        });
+        ProgressTracker::new(reporter, decision_periods, states_per_period)
+    }
+
+    // Runs solve_state on every state of the period in one parallel loop. The calling thread checks for Ctrl-C and
+    // reports progress while it runs, as in rust::interrupt::run_interruptible, and once that fails the states left are skipped
+    pub fn solve_period<S: Sync>(
+        &self,
+        period: usize,
+        states: &[S],
+        solve_state: impl Fn(&S) + Sync,
+    ) -> PyResult<()> {
+        let states_done = AtomicUsize::new(0);
+        rust::interrupt::run_interruptible(
+            |cancel| {
+                states.par_iter().for_each(|state| {
+                    if cancel.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    solve_state(state);
+                    states_done.fetch_add(1, Ordering::Relaxed);
+                })
+            },
+            || self.report(period, states_done.load(Ordering::Relaxed)),
+        )?;
+        self.report(period, states.len())
+    }
+
+    // Periods are solved backwards from decision_periods down to 1
+    pub fn report(&self, period: usize, states_done: usize) -> PyResult<()> {
+        let reporter = match &self.reporter {
+            Some(reporter) => reporter,
+            None => return Ok(()),
+        };
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let total = self.decision_periods * self.states_per_period;
+        let done = (self.decision_periods - period) * self.states_per_period + states_done;
+        let eta = if done > 0 {
+            Some(elapsed * (total - done) as f64 / done as f64)
+        } else {
+            None
+        };
+        reporter.report(&ProgressUpdate {
+            period,
+            states_done,
+            elapsed,
+            eta,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::exceptions::PyValueError;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // Period, states done and eta of an update
+    type Recorded = (usize, usize, Option<f64>);
+
+    // Keeps every update, or fails every report when failing is set
+    struct Recorder {
+        updates: Arc<Mutex<Vec<Recorded>>>,
+        failing: bool,
+    }
+
+    impl ProgressReporter for Recorder {
+        fn report(&self, update: &ProgressUpdate) -> PyResult<()> {
+            if self.failing {
+                return Err(PyValueError::new_err("stop"));
+            }
+            self.updates.lock().unwrap().push((update.period, update.states_done, update.eta));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn every_period_reports_its_states() {
+        pyo3::prepare_freethreaded_python();
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let states: Vec<usize> = (0..10).collect();
+        let tracker = ProgressTracker::new(
+            Some(Box::new(Recorder { updates: updates.clone(), failing: false })),
+            2,
+            states.len(),
+        );
+        for period in (1..=2).rev() {
+            tracker
+                .solve_period(period, &states, |_| std::thread::sleep(Duration::from_millis(10)))
+                .unwrap();
+        }
+        let updates = updates.lock().unwrap();
+        // Periods count down and states only go up within one
+        for pair in updates.windows(2) {
+            assert!(pair[1].0 < pair[0].0 || (pair[1].0 == pair[0].0 && pair[1].1 >= pair[0].1), "{:?}", pair);
+        }
+        for period in 1..=2 {
+            let last = updates.iter().rev().find(|update| update.0 == period).unwrap();
+            assert_eq!(last.1, states.len());
+        }
+        assert_eq!(updates.last().unwrap().2, Some(0.0));
+    }
+
+    #[test]
+    fn failing_reporter_stops_the_period() {
+        pyo3::prepare_freethreaded_python();
+        let states: Vec<usize> = (0..40).collect();
+        let tracker = ProgressTracker::new(
+            Some(Box::new(Recorder { updates: Arc::new(Mutex::new(Vec::new())), failing: true })),
+            1,
+            states.len(),
+        );
+        let solved = AtomicUsize::new(0);
+        let result = tracker.solve_period(1, &states, |_| {
+            std::thread::sleep(Duration::from_millis(10));
+            solved.fetch_add(1, Ordering::Relaxed);
+        });
+        assert!(result.is_err());
+        assert!(solved.load(Ordering::Relaxed) < states.len());
+    }
+}
@@ -0,0 +1,281 @@
+// Long-run behaviour of a stationary policy. The policy's actions in one period induce a Markov chain on the states
+// through the same transitions as future_costs, its stationary distribution weights the per-period metrics.
+use crate::rust;
+use crate::rust::policies::{Policy, State};
+use std::collections::HashMap;
+
+pub struct StationaryReport {
+    pub distribution: HashMap<State, f64>,
+    pub iterations: usize,
+    pub converged: bool,
+    // Expected immediate cost per period
+    pub average_cost: f64,
+    // Share of the demand faced at (store A, store B) met from the store's own stock. Demand faced is counted after walkers
+    // and emergency transhipments, where it is served, so the rates stay within 1
+    pub store_fill_rate: (f64, f64),
+    // Share of the demand faced at (store A, store B) met from store stock or direct from the warehouse
+    pub fill_rate: (f64, f64),
+    // Share of the demand met at (store A, store B) that was shipped direct from the warehouse
+    pub dfw_share: (f64, f64),
+    // Probability of transhipping in a period and the expected units transhipped per period
+    pub transhipment_frequency: f64,
+    pub mean_transhipment: f64,
+}
+
+// Per-period quantities of a state under the policy, in expectation over demand and a randomised policy
+#[derive(Default)]
+struct StateMetrics {
+    cost: f64,
+    demand: (f64, f64),
+    from_store: (f64, f64),
+    from_warehouse: (f64, f64),
+    transhipping: f64,
+    transhipped: f64,
+}
+
+// Finds the stationary distribution by power iteration from initial_state on the lazy chain (1/2 I + 1/2 P),
+// which has the same stationary distribution but cannot be periodic. Stops once the L1 change is below tolerance.
+pub fn stationary_report(
+    policy_constructor: &rust::policy_contructor::OptimalPolicy,
+    policy: &dyn Policy,
+    period: usize,
+    initial_state: State,
+    tolerance: f64,
+    max_iterations: usize,
+) -> StationaryReport {
+    let store_expectation = policy_constructor.expectation_all_stores();
+    let warehouse_expectation = policy_constructor.expectation_all_warehouse();
+    let states: Vec<State> = policy_constructor.construct_state_space_iterator().collect();
+    let index: HashMap<State, usize> = states
+        .iter()
+        .enumerate()
+        .map(|(i, state)| (*state, i))
+        .collect();
+
+    // Sparse transition rows and metrics of every state
+    let mut transitions: Vec<Vec<(usize, f64)>> = Vec::with_capacity(states.len());
+    let mut metrics: Vec<StateMetrics> = Vec::with_capacity(states.len());
+    for state in &states {
+        let mut row: HashMap<usize, f64> = HashMap::new();
+        let mut state_metrics = StateMetrics::default();
+        let dfw_threshold = policy
+            .dfw_threshold(period, *state)
+            .unwrap_or(policy_constructor.dfw_threshold);
+        for (action, action_prob) in policy.action_distribution(period, *state) {
+            let (wh_order, st_a_order, st_b_order, t_a_to_b, t_b_to_a) = action;
+            let post_state = (
+                state.0 - st_a_order - st_b_order,
+                state.1 - t_a_to_b + t_b_to_a,
+                state.2 - t_b_to_a + t_a_to_b,
+            );
+            rust::value_function::for_each_transition_rationed(
+                policy_constructor,
+                post_state,
+                (wh_order, st_a_order, st_b_order),
+                dfw_threshold,
+                |next_state, prob| {
+                    *row.entry(index[&next_state]).or_insert(0.0) += action_prob * prob;
+                },
+            );
+            let (demand, from_store, from_warehouse) =
+                fulfilment(policy_constructor, post_state, dfw_threshold);
+            let cost = if dfw_threshold == policy_constructor.dfw_threshold {
+                rust::value_function::immediate_cost(
+                    policy_constructor,
+                    *state,
+                    action,
+                    &store_expectation,
+                    &warehouse_expectation,
+                )
+            } else {
+                rust::value_function::immediate_cost_rationed(
+                    policy_constructor,
+                    *state,
+                    action,
+                    dfw_threshold,
+                )
+            };
+            state_metrics.cost += action_prob * cost;
+            state_metrics.demand.0 += action_prob * demand.0;
+            state_metrics.demand.1 += action_prob * demand.1;
+            state_metrics.from_store.0 += action_prob * from_store.0;
+            state_metrics.from_store.1 += action_prob * from_store.1;
+            state_metrics.from_warehouse.0 += action_prob * from_warehouse.0;
+            state_metrics.from_warehouse.1 += action_prob * from_warehouse.1;
+            if t_a_to_b + t_b_to_a > 0 {
+                state_metrics.transhipping += action_prob;
+                state_metrics.transhipped += action_prob * (t_a_to_b + t_b_to_a) as f64;
+            }
+        }
+        transitions.push(row.into_iter().collect());
+        metrics.push(state_metrics);
+    }
+
+    let mut pi = vec![0.0; states.len()];
+    pi[index[&initial_state]] = 1.0;
+    let mut iterations = 0;
+    let mut converged = false;
+    while iterations < max_iterations {
+        iterations += 1;
+        let mut next_pi: Vec<f64> = pi.iter().map(|prob| 0.5 * prob).collect();
+        for (i, row) in transitions.iter().enumerate() {
+            for (j, prob) in row {
+                next_pi[*j] += 0.5 * pi[i] * prob;
+            }
+        }
+        // Demand beyond D_MAX is dropped from the pmf so renormalise
+        let total: f64 = next_pi.iter().sum();
+        let mut change = 0.0;
+        for (next, prob) in next_pi.iter_mut().zip(pi.iter()) {
+            *next /= total;
+            change += (*next - prob).abs();
+        }
+        pi = next_pi;
+        if change < tolerance {
+            converged = true;
+            break;
+        }
+    }
+
+    let mut long_run = StateMetrics::default();
+    for (prob, state_metrics) in pi.iter().zip(metrics.iter()) {
+        long_run.cost += prob * state_metrics.cost;
+        long_run.demand.0 += prob * state_metrics.demand.0;
+        long_run.demand.1 += prob * state_metrics.demand.1;
+        long_run.from_store.0 += prob * state_metrics.from_store.0;
+        long_run.from_store.1 += prob * state_metrics.from_store.1;
+        long_run.from_warehouse.0 += prob * state_metrics.from_warehouse.0;
+        long_run.from_warehouse.1 += prob * state_metrics.from_warehouse.1;
+        long_run.transhipping += prob * state_metrics.transhipping;
+        long_run.transhipped += prob * state_metrics.transhipped;
+    }
+    let demand = long_run.demand;
+    let ratio = |num: f64, den: f64| if den > 0.0 { num / den } else { 0.0 };
+    let met = (
+        long_run.from_store.0 + long_run.from_warehouse.0,
+        long_run.from_store.1 + long_run.from_warehouse.1,
+    );
+
+    StationaryReport {
+        distribution: states
+            .iter()
+            .zip(pi.iter())
+            .filter(|(_, prob)| **prob > 0.0)
+            .map(|(state, prob)| (*state, *prob))
+            .collect(),
+        iterations,
+        converged,
+        average_cost: long_run.cost,
+        store_fill_rate: (
+            ratio(long_run.from_store.0, demand.0),
+            ratio(long_run.from_store.1, demand.1),
+        ),
+        fill_rate: (ratio(met.0, demand.0), ratio(met.1, demand.1)),
+        dfw_share: (
+            ratio(long_run.from_warehouse.0, met.0),
+            ratio(long_run.from_warehouse.1, met.1),
+        ),
+        transhipment_frequency: long_run.transhipping,
+        mean_transhipment: long_run.transhipped,
+    }
+}
+
+// Expected demand faced at (store A, store B) and the units met from their stock and direct from the warehouse in a post-action state.
+// As in future_costs, excess demand is offered warehouse stock in the policy's DFW order and each customer accepts with their store's p.
+// Customers who walk to the other store, or are sent an emergency transhipment from it, are counted in the demand and fulfilment
+// of the store that served them. The units met from store stock are taken from the outcome, as the demand faced less DFW and
+// the customers lost, so they always add up to the demand.
+// DFW is limited to the warehouse stock above dfw_threshold.
+fn fulfilment(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    state: State,
+    dfw_threshold: usize,
+) -> ((f64, f64), (f64, f64), (f64, f64)) {
+    let mut demand = (0.0, 0.0);
+    let mut from_store = (0.0, 0.0);
+    let mut from_warehouse = (0.0, 0.0);
+    policy.for_each_demand_outcome_rationed(state, dfw_threshold, |faced, dfw, online, emergency, prob| {
+        let lost = policy.unfulfilled(state, (faced, dfw, online, emergency));
+        demand.0 += prob * faced.0 as f64;
+        demand.1 += prob * faced.1 as f64;
+        from_store.0 += prob * (faced.0 - dfw.0 - lost.0) as f64;
+        from_store.1 += prob * (faced.1 - dfw.1 - lost.1) as f64;
+        from_warehouse.0 += prob * dfw.0 as f64;
+        from_warehouse.1 += prob * dfw.1 as f64;
+    });
+    (demand, from_store, from_warehouse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policies::base_stock::{AllocationRule, RegularBaseStock};
+    use crate::rust::policies::{Composite, NoTranshipment};
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::{ModelParams, OptimalPolicy, PerDirection, PerStore};
+
+    #[test]
+    fn emergency_transhipments_keep_demand_accounted_for() {
+        // Free emergency transhipments and a unit lost sales cost with no other store cost, so the store cost is the lost sales
+        let model = ModelParams {
+            h_s: PerStore(0.0, 0.0),
+            c_u_s: PerStore(1.0, 1.0),
+            c_p: PerStore(0.0, 0.0),
+            c_ts_emergency: Some(PerDirection(0.0, 0.0)),
+            ..small_model()
+        };
+        let policy = OptimalPolicy::new(&model, (0, 0));
+        let mut emergency_units = 0.0;
+        for state in policy.construct_state_space_iterator() {
+            let (demand, from_store, from_warehouse) = fulfilment(&policy, state, 0);
+            let mut lost = 0.0;
+            policy.for_each_demand_outcome_rationed(state, 0, |faced, dfw, online, emergency, prob| {
+                lost += prob * policy.store_outcome_cost(state, (faced, dfw, online, emergency));
+                emergency_units += prob * (emergency.0 + emergency.1) as f64;
+            });
+            // Emergencies and walkers only move customers between the stores
+            let met = from_store.0 + from_store.1 + from_warehouse.0 + from_warehouse.1;
+            assert!((met + lost - (demand.0 + demand.1)).abs() < 1e-9, "state {:?}", state);
+            assert!((demand.0 + demand.1 - 3.5).abs() < 1e-9, "state {:?}", state);
+            // Units sent in an emergency come out of the sending store's stock
+            assert!(from_store.0 <= state.1 as f64 + 1e-9 && from_store.1 <= state.2 as f64 + 1e-9, "state {:?}", state);
+        }
+        assert!(emergency_units > 0.0);
+    }
+
+    #[test]
+    fn stationary_distribution_sums_to_one() {
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let mut policy = Composite {
+            ordering: Box::new(RegularBaseStock::new((5, 3, 3), None, AllocationRule::LargestRequest)),
+            transhipment: Box::new(NoTranshipment),
+        };
+        policy.setup(2);
+        let report = stationary_report(&policy_constructor, &policy, 1, (0, 0, 0), 1e-12, 100000);
+        assert!(report.converged);
+        assert!((report.distribution.values().sum::<f64>() - 1.0).abs() < 1e-9);
+        for (store_fill_rate, fill_rate) in [
+            (report.store_fill_rate.0, report.fill_rate.0),
+            (report.store_fill_rate.1, report.fill_rate.1),
+        ] {
+            assert!(0.0 < store_fill_rate && store_fill_rate <= fill_rate && fill_rate <= 1.0);
+        }
+        // The average cost is the immediate cost weighted by the stationary distribution
+        let store_expectation = policy_constructor.expectation_all_stores();
+        let warehouse_expectation = policy_constructor.expectation_all_warehouse();
+        let average_cost: f64 = report
+            .distribution
+            .iter()
+            .map(|(state, prob)| {
+                prob * rust::value_function::immediate_cost(
+                    &policy_constructor,
+                    *state,
+                    policy.action(1, *state),
+                    &store_expectation,
+                    &warehouse_expectation,
+                )
+            })
+            .sum();
+        assert!((report.average_cost - average_cost).abs() < 1e-9);
+    }
+}
@@ -2,8 +2,8 @@
 use crate::rust;
 //use itertools::Itertools;
 use dashmap::DashMap;
-use std::cmp::max;
-use std::cmp::min;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
 use std::collections::HashMap;
 use std::usize;
 
@@ -20,29 +20,18 @@ pub fn value_function_optimal_pol(
     // generate action space
     let mut best_action: Option<((usize, usize, usize, usize, usize), f64)> = None;
 
-    for (wh_order, st_a_order, st_b_order, t_a_to_b, t_b_to_a) in action_space {
-        // Post transhipment and store ordering state. Note because of LT=1, the orders don't arrive till the future cost part
-        let post_state = (
-            pre_action_state.0 - st_a_order - st_b_order, // Wh
-            pre_action_state.1 - t_a_to_b + t_b_to_a,     // Store A
-            pre_action_state.2 - t_b_to_a + t_a_to_b,     // Store B
+    for action in action_space {
+        let (im_cost, fut_cost) = action_cost_breakdown(
+            policy,
+            pre_action_state,
+            v_t_plus_1,
+            *action,
+            store_expectation,
+            warehouse_expectation,
         );
-        let im_cost = policy.c_ts * (t_a_to_b + t_b_to_a) as f64
-            + warehouse_expectation[&post_state]
-            + store_expectation[&post_state];
-        let fut_cost: f64 = policy.gamma
-            * future_costs(
-                policy,
-                post_state,
-                (*wh_order, *st_a_order, *st_b_order),
-                v_t_plus_1,
-            );
         let total_cost = im_cost + fut_cost;
         if best_action.is_none() || total_cost < best_action.unwrap().1 {
-            best_action = Some((
-                (*wh_order, *st_a_order, *st_b_order, *t_a_to_b, *t_b_to_a),
-                total_cost,
-            ));
+            best_action = Some((*action, total_cost));
         }
     }
     best_action.unwrap()
@@ -57,7 +46,26 @@ pub fn value_function_pol_eval(
     store_expectation: &HashMap<(usize, usize, usize), f64>,
     warehouse_expectation: &HashMap<(usize, usize, usize), f64>,
 ) -> f64 {
-    // generate action space
+    let (im_cost, fut_cost) = action_cost_breakdown(
+        policy,
+        pre_action_state,
+        v_t_plus_1,
+        action,
+        store_expectation,
+        warehouse_expectation,
+    );
+    im_cost + fut_cost
+}
+
+// Immediate cost and discounted future cost of taking an action, their sum is the Q-value
+pub fn action_cost_breakdown(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    pre_action_state: (usize, usize, usize),
+    v_t_plus_1: &HashMap<(usize, usize, usize), f64>,
+    action: (usize, usize, usize, usize, usize),
+    store_expectation: &HashMap<(usize, usize, usize), f64>,
+    warehouse_expectation: &HashMap<(usize, usize, usize), f64>,
+) -> (f64, f64) {
     let (wh_order, st_a_order, st_b_order, t_a_to_b, t_b_to_a) = action;
     // Post transhipment and store ordering state. Note because of LT=1, the orders don't arrive till the future cost part
     let post_state = (
@@ -65,9 +73,13 @@ pub fn value_function_pol_eval(
         pre_action_state.1 - t_a_to_b + t_b_to_a,     // Store A
         pre_action_state.2 - t_b_to_a + t_a_to_b,     // Store B
     );
-    let im_cost = policy.c_ts * (t_a_to_b + t_b_to_a) as f64
-        + warehouse_expectation[&post_state]
-        + store_expectation[&post_state];
+    let im_cost = immediate_cost(
+        policy,
+        pre_action_state,
+        action,
+        store_expectation,
+        warehouse_expectation,
+    );
     let fut_cost: f64 = policy.gamma
         * future_costs(
             policy,
@@ -75,8 +87,155 @@ pub fn value_function_pol_eval(
             (wh_order, st_a_order, st_b_order),
             v_t_plus_1,
         );
-    let total_cost = im_cost + fut_cost;
-    total_cost
+    (im_cost, fut_cost)
+}
+
+// Expected cost incurred this period, transhipment costs plus the holding, shortage and DFW costs of the post-action state
+pub fn immediate_cost(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    pre_action_state: (usize, usize, usize),
+    action: (usize, usize, usize, usize, usize),
+    store_expectation: &HashMap<(usize, usize, usize), f64>,
+    warehouse_expectation: &HashMap<(usize, usize, usize), f64>,
+) -> f64 {
+    let (_, st_a_order, st_b_order, t_a_to_b, t_b_to_a) = action;
+    let post_state = (
+        pre_action_state.0 - st_a_order - st_b_order,
+        pre_action_state.1 - t_a_to_b + t_b_to_a,
+        pre_action_state.2 - t_b_to_a + t_a_to_b,
+    );
+    policy.transhipment_cost(t_a_to_b, t_b_to_a)
+        + warehouse_expectation[&post_state]
+        + store_expectation[&post_state]
+}
+
+// As action_cost_breakdown with DFW limited to the warehouse stock above dfw_threshold.
+// The expectations are worked out for the one post-action state rather than looked up
+pub fn action_cost_breakdown_rationed(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    pre_action_state: (usize, usize, usize),
+    v_t_plus_1: &HashMap<(usize, usize, usize), f64>,
+    action: (usize, usize, usize, usize, usize),
+    dfw_threshold: usize,
+) -> (f64, f64) {
+    let (wh_order, st_a_order, st_b_order, t_a_to_b, t_b_to_a) = action;
+    let post_state = (
+        pre_action_state.0 - st_a_order - st_b_order,
+        pre_action_state.1 - t_a_to_b + t_b_to_a,
+        pre_action_state.2 - t_b_to_a + t_a_to_b,
+    );
+    let im_cost = immediate_cost_rationed(policy, pre_action_state, action, dfw_threshold);
+    let fut_cost: f64 = policy.gamma
+        * future_costs_rationed(
+            policy,
+            post_state,
+            (wh_order, st_a_order, st_b_order),
+            dfw_threshold,
+            v_t_plus_1,
+        );
+    (im_cost, fut_cost)
+}
+
+// As immediate_cost with DFW limited to the warehouse stock above dfw_threshold
+pub fn immediate_cost_rationed(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    pre_action_state: (usize, usize, usize),
+    action: (usize, usize, usize, usize, usize),
+    dfw_threshold: usize,
+) -> f64 {
+    let (_, st_a_order, st_b_order, t_a_to_b, t_b_to_a) = action;
+    let post_state = (
+        pre_action_state.0 - st_a_order - st_b_order,
+        pre_action_state.1 - t_a_to_b + t_b_to_a,
+        pre_action_state.2 - t_b_to_a + t_a_to_b,
+    );
+    policy.transhipment_cost(t_a_to_b, t_b_to_a)
+        + policy
+            .expectation_warehouse_rationed(post_state, dfw_threshold)
+            .unwrap()
+        + policy
+            .expectation_store_rationed(post_state, dfw_threshold)
+            .unwrap()
+}
+
+// Every feasible action in a state with (immediate cost, discounted future cost, total cost), cheapest first.
+// dfw_threshold overrides the model's fixed threshold, e.g. with one chosen by optimal_policy_par
+pub fn q_values(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    pre_action_state: (usize, usize, usize),
+    v_t_plus_1: &HashMap<(usize, usize, usize), f64>,
+    store_expectation: &HashMap<(usize, usize, usize), f64>,
+    warehouse_expectation: &HashMap<(usize, usize, usize), f64>,
+    dfw_threshold: Option<usize>,
+) -> Vec<(rust::policies::Action, (f64, f64, f64))> {
+    let mut q = policy
+        .generate_action_space(pre_action_state)
+        .into_iter()
+        .map(|action| {
+            let (im_cost, fut_cost) = match dfw_threshold {
+                Some(dfw_threshold) => action_cost_breakdown_rationed(
+                    policy,
+                    pre_action_state,
+                    v_t_plus_1,
+                    action,
+                    dfw_threshold,
+                ),
+                None => action_cost_breakdown(
+                    policy,
+                    pre_action_state,
+                    v_t_plus_1,
+                    action,
+                    store_expectation,
+                    warehouse_expectation,
+                ),
+            };
+            (action, (im_cost, fut_cost, im_cost + fut_cost))
+        })
+        .collect::<Vec<_>>();
+    // Stable sort so equal-cost actions keep the action space order, the first is the one the solvers pick
+    q.sort_by(|a, b| a.1 .2.total_cmp(&b.1 .2));
+    q
+}
+
+// Calls f(next_state, probability) for every next state reachable from the post-action state once demand is met,
+// with DFW limited to the warehouse stock above dfw_threshold, and the orders (warehouse, store A, store B) arrive
+pub fn for_each_transition_rationed(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    state: (usize, usize, usize),
+    orders: (usize, usize, usize),
+    dfw_threshold: usize,
+    mut f: impl FnMut((usize, usize, usize), f64),
+) {
+    policy.for_each_demand_outcome_rationed(state, dfw_threshold, |demand, dfw, online, _emergency, prob| {
+        f(next_state(state, orders, demand, dfw, online), prob)
+    });
+}
+
+// State after demand (store A, store B), DFW units (j, k) and online orders (filled, lost) have taken their stock
+// from the post-action state and the orders (warehouse, store A, store B) arrive
+pub fn next_state(
+    state: (usize, usize, usize),
+    orders: (usize, usize, usize),
+    demand: (usize, usize),
+    dfw: (usize, usize),
+    online: (usize, usize),
+) -> (usize, usize, usize) {
+    (
+        state.0 - (online.0 + dfw.0 + dfw.1) + orders.0,
+        state.1.saturating_sub(demand.0) + orders.1,
+        state.2.saturating_sub(demand.1) + orders.2,
+    )
+}
+
+// Draws a next state of for_each_transition_rationed under the model's dfw_threshold
+pub fn sample_transition(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    state: (usize, usize, usize),
+    orders: (usize, usize, usize),
+    rng: &mut impl rand::Rng,
+) -> (usize, usize, usize) {
+    let (demand, dfw, online, _emergency) = policy.sample_demand_outcome(state, rng);
+    next_state(state, orders, demand, dfw, online)
 }
 
 pub fn future_costs(
@@ -84,99 +243,169 @@ pub fn future_costs(
     state: (usize, usize, usize),
     orders: (usize, usize, usize),
     v_t_plus_1: &HashMap<(usize, usize, usize), f64>,
+) -> f64 {
+    future_costs_rationed(policy, state, orders, policy.dfw_threshold, v_t_plus_1)
+}
+
+pub fn future_costs_rationed(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    state: (usize, usize, usize),
+    orders: (usize, usize, usize),
+    dfw_threshold: usize,
+    v_t_plus_1: &HashMap<(usize, usize, usize), f64>,
 ) -> f64 {
     let mut exp = 0.0;
-    for (db_val, db_pmf_i) in policy.db_pmf.iter().enumerate() {
-        let sb_post_demand = max(state.2 as isize - db_val as isize, 0) as usize + orders.2;
-
-        // If the second store can satisfy demand then the warehouse next state is limited just store A
-        // easy peasy lemon squeezy
-        if (state.2 as isize - db_val as isize) >= 0 {
-            for (da_val, da_pmf_i) in policy.da_pmf.iter().enumerate() {
-                let sa_post_demand = max(state.1 as isize - da_val as isize, 0) as usize + orders.1;
-                if (state.1 as isize - da_val as isize) >= 0 {
-                    // No stores took stock therefore the warehouse just adds its order
-                    let wh_post_demand = state.0 + orders.0;
-                    exp += da_pmf_i
-                        * db_pmf_i
-                        * v_t_plus_1[&(wh_post_demand, sa_post_demand, sb_post_demand)]
-                } else {
-                    // See how much store A asks for
-                    let excess = da_val as isize - state.1 as isize;
-                    // See how much stock we can fulfil from the warehouse
-                    let max_beta_sa: usize = min(max(excess, 0), state.0 as isize) as usize;
-                    // Iterate over this and calculate next state probability
-                    for j in 0..max_beta_sa + 1 {
-                        let wh_post_demand = state.0 - j + orders.0;
-                        exp += da_pmf_i
-                            * db_pmf_i
-                            * policy.binom_pmf[max_beta_sa][j]
-                            * v_t_plus_1[&(wh_post_demand, sa_post_demand, sb_post_demand)]
-                    }
-                }
-            }
-        } else {
-            // In this case store b needs to take stock from the warehouse
-
-            // See if store a took stock from the warehouse
-            for (da_val, da_pmf_i) in policy.da_pmf.iter().enumerate() {
-                let sa_post_demand = max(state.1 as isize - da_val as isize, 0) as usize + orders.1;
-                if (state.1 as isize - da_val as isize) >= 0 {
-                    // In this case store a took no stock from the warehouse
-                    let excess = db_val as isize - state.2 as isize;
-                    // See how much stock we can fulfil from the warehouse
-                    let max_beta_sb: usize = min(max(excess, 0), state.0 as isize) as usize;
-                    //let sb_post_demand = max(state.2 as isize - db_val as isize,0) as usize + orders.2;
-
-                    for j in 0..max_beta_sb + 1 {
-                        let wh_post_demand = state.0 - j + orders.0;
-                        exp += da_pmf_i
-                            * db_pmf_i
-                            * policy.binom_pmf[max_beta_sb][j]
-                            * v_t_plus_1[&(wh_post_demand, sa_post_demand, sb_post_demand)]
-                    }
-                } else {
-                    // Store A took stock from the warehouse
-                    let excess_s1 = da_val as isize - state.1 as isize;
-                    let sa_post_demand =
-                        max(state.1 as isize - da_val as isize, 0) as usize + orders.1;
-                    // See how much stock we can fulfil from the warehouse
-                    let max_beta_sa: usize = min(max(excess_s1, 0), state.0 as isize) as usize;
-                    //let sb_post_demand = max(state.2 as isize - db_val as isize,0) as usize + orders.2;
-
-                    for j in 0..max_beta_sa + 1 {
-                        // Number of remaining stock we can fulfil from the warehouse
-                        let remaining_warehouse_stock = state.0 - j;
-                        let excess = db_val as isize - state.2 as isize;
-                        // See how much stock we can fulfil from the warehouse
-                        let max_beta_sb: usize =
-                            min(max(excess, 0), remaining_warehouse_stock as isize) as usize;
-                        for k in 0..max_beta_sb + 1 {
-                            let wh_post_demand = state.0 - (j + k) + orders.0;
-                            exp += da_pmf_i
-                                * db_pmf_i
-                                * policy.binom_pmf[max_beta_sa][j]
-                                * policy.binom_pmf[max_beta_sb][k]
-                                * v_t_plus_1[&(wh_post_demand, sa_post_demand, sb_post_demand)]
-                        }
-                    }
-                }
+    for_each_transition_rationed(policy, state, orders, dfw_threshold, |next_state, prob| {
+        exp += prob * v_t_plus_1[&next_state]
+    });
+    exp
+}
+
+// Store plus warehouse expected cost of every post-action state under every DFW threshold, keyed by (state, threshold).
+// Thresholds run from 0 to the warehouse level, where no DFW is offered, higher thresholds change nothing
+pub type RationedExpectations = HashMap<((usize, usize, usize), usize), f64>;
+
+pub fn rationed_expectations(
+    policy: &rust::policy_contructor::OptimalPolicy,
+) -> PyResult<RationedExpectations> {
+    let mut expectations = HashMap::new();
+    for state in policy.construct_state_space_iterator() {
+        for dfw_threshold in 0..state.0 + 1 {
+            let exp = policy.expectation_store_rationed(state, dfw_threshold)?
+                + policy.expectation_warehouse_rationed(state, dfw_threshold)?;
+            expectations.insert((state, dfw_threshold), exp);
+        }
+    }
+    Ok(expectations)
+}
+
+// As value_function_optimal_pol but also choosing the DFW threshold for the post-action state.
+// Returns the best action, its threshold and the value, ties go to the earlier action and the lower threshold
+pub fn value_function_optimal_pol_rationed(
+    policy: &rust::policy_contructor::OptimalPolicy,
+    pre_action_state: (usize, usize, usize),
+    v_t_plus_1: &HashMap<(usize, usize, usize), f64>,
+    action_space: &Vec<(usize, usize, usize, usize, usize)>,
+    rationed_expectation: &RationedExpectations,
+) -> (rust::policies::Action, usize, f64) {
+    let mut best_action: Option<(rust::policies::Action, usize, f64)> = None;
+
+    for action in action_space {
+        let (wh_order, st_a_order, st_b_order, t_a_to_b, t_b_to_a) = *action;
+        let post_state = (
+            pre_action_state.0 - st_a_order - st_b_order,
+            pre_action_state.1 - t_a_to_b + t_b_to_a,
+            pre_action_state.2 - t_b_to_a + t_a_to_b,
+        );
+        for dfw_threshold in 0..post_state.0 + 1 {
+            let im_cost = policy.transhipment_cost(t_a_to_b, t_b_to_a)
+                + rationed_expectation[&(post_state, dfw_threshold)];
+            let fut_cost = policy.gamma
+                * future_costs_rationed(
+                    policy,
+                    post_state,
+                    (wh_order, st_a_order, st_b_order),
+                    dfw_threshold,
+                    v_t_plus_1,
+                );
+            let total_cost = im_cost + fut_cost;
+            if best_action.is_none() || total_cost < best_action.unwrap().2 {
+                best_action = Some((*action, dfw_threshold, total_cost));
             }
         }
     }
-    exp
+    best_action.unwrap()
 }
 
+// Terminal value of each state. Either a per-unit cost for (warehouse, store A, store B) stock,
+// where negative values are a salvage value and positive values a penalty, or a table with a value for every state.
+// With neither given every state has zero terminal cost.
 pub fn terminal_cost(
     policy: &rust::policy_contructor::OptimalPolicy,
-    cost: Option<f64>,
-) -> DashMap<(usize, usize, usize), f64> {
+    unit_cost: Option<(f64, f64, f64)>,
+    values: Option<&HashMap<(usize, usize, usize), f64>>,
+) -> PyResult<DashMap<(usize, usize, usize), f64>> {
+    if unit_cost.is_some() && values.is_some() {
+        return Err(PyValueError::new_err(
+            "Give either a per-unit terminal cost or a terminal value table, not both",
+        ));
+    }
     let ss = policy.construct_state_space_iterator();
     let v_t = DashMap::new();
-    let cost = cost.unwrap_or(0.0);
+    let (c_wh, c_sa, c_sb) = unit_cost.unwrap_or((0.0, 0.0, 0.0));
     for state in ss {
         let state = (state.0, state.1, state.2);
+        let cost = match values {
+            Some(values) => match values.get(&state) {
+                Some(cost) => *cost,
+                None => {
+                    return Err(PyValueError::new_err(format!(
+                        "Terminal value table is missing state {:?}",
+                        state
+                    )))
+                }
+            },
+            None => c_wh * state.0 as f64 + c_sa * state.1 as f64 + c_sb * state.2 as f64,
+        };
         v_t.insert(state, cost);
     }
-    v_t
+    Ok(v_t)
+}
+
+// Stores a copy of the value function for period t, keyed by (t, wh, sa, sb), so every period can be returned
+pub fn record_period_values(
+    v_all: &mut HashMap<(usize, usize, usize, usize), f64>,
+    t: usize,
+    v_t: impl Iterator<Item = ((usize, usize, usize), f64)>,
+) {
+    for (state, value) in v_t {
+        v_all.insert((t, state.0, state.1, state.2), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust::policy_contructor::tests::small_model;
+    use crate::rust::policy_contructor::OptimalPolicy;
+
+    #[test]
+    fn cheapest_q_value_is_the_optimal_action() {
+        let policy_constructor = OptimalPolicy::new(&small_model(), (0, 0));
+        let terminal = terminal_cost(&policy_constructor, Some((1.0, 1.0, 1.0)), None).unwrap();
+        let v_plus_1: HashMap<(usize, usize, usize), f64> =
+            terminal.iter().map(|entry| (*entry.key(), *entry.value())).collect();
+        let store_expectation = policy_constructor.expectation_all_stores();
+        let warehouse_expectation = policy_constructor.expectation_all_warehouse();
+        let action_space = policy_constructor.construct_action_space();
+        for state in [(0, 0, 0), (5, 0, 3), (3, 2, 0), (4, 1, 1)] {
+            let q = q_values(
+                &policy_constructor,
+                state,
+                &v_plus_1,
+                &store_expectation,
+                &warehouse_expectation,
+                None,
+            );
+            assert_eq!(q.len(), action_space[&state].len());
+            for pair in q.windows(2) {
+                assert!(pair[0].1 .2 <= pair[1].1 .2);
+            }
+            for (action, (im_cost, fut_cost, total_cost)) in &q {
+                assert_eq!(im_cost + fut_cost, *total_cost);
+                let im = immediate_cost(&policy_constructor, state, *action, &store_expectation, &warehouse_expectation);
+                assert_eq!(*im_cost, im);
+            }
+            let (action, value) = value_function_optimal_pol(
+                &policy_constructor,
+                state,
+                &v_plus_1,
+                &action_space[&state],
+                &store_expectation,
+                &warehouse_expectation,
+            );
+            assert_eq!(q[0].0, action, "state {:?}", state);
+            assert_eq!(q[0].1 .2, value);
+        }
+    }
 }